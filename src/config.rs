@@ -0,0 +1,65 @@
+use std::path::{Path, PathBuf};
+use glob::Pattern;
+use ini::Ini;
+use log::error;
+use crate::repo::utils::option_list;
+
+// Общие настройки konpac из секции [options] и внешние транспорты из секции [transports]
+#[derive(Clone)]
+pub struct Config {
+    // Сколько архивов загружается одновременно
+    pub parallel_downloads: usize,
+    // Пакеты, которые не обновляются и не меняются транзакциями (шаблоны имен)
+    pub ignore_pkg: Vec<Pattern>,
+    // Прокси для всех загрузок и адреса, которые идут мимо него
+    pub proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    // Программы загрузки для схем URL: "s3 = exec:/usr/local/bin/konpac-s3"
+    pub transports: Vec<(String, PathBuf)>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config { parallel_downloads: 5, ignore_pkg: Vec::new(), proxy: None, no_proxy: None, transports: Vec::new() }
+    }
+}
+
+impl Config {
+    // Отсутствующий файл или ключ означает значение по умолчанию
+    pub fn load(config_file: &Path) -> Self {
+        let mut config = Config::default();
+        let ini = match Ini::load_from_file(config_file) {
+            Ok(ini) => ini,
+            Err(ini::Error::Io(_)) => return config,
+            Err(e) => {
+                error!("Не удалось прочитать {:?}: {}", config_file, e);
+                return config;
+            }
+        };
+        if let Some(section) = ini.section(Some("options")) {
+            if let Some(value) = section.get("parallel_downloads") {
+                match value.parse::<usize>() {
+                    Ok(parallel) if parallel > 0 => config.parallel_downloads = parallel,
+                    _ => error!("Некорректное значение parallel_downloads '{}', используется {}", value, config.parallel_downloads),
+                }
+            }
+            config.proxy = section.get("proxy").map(|proxy| proxy.to_string());
+            config.no_proxy = section.get("no_proxy").map(|no_proxy| no_proxy.to_string());
+            for pattern in option_list(section, "ignore_pkg") {
+                match Pattern::new(&pattern) {
+                    Ok(pattern) => config.ignore_pkg.push(pattern),
+                    Err(e) => error!("Некорректный шаблон ignore_pkg '{}': {}", pattern, e),
+                }
+            }
+        }
+        if let Some(section) = ini.section(Some("transports")) {
+            for (scheme, value) in section.iter() {
+                match value.strip_prefix("exec:") {
+                    Some(helper) if !helper.is_empty() => config.transports.push((scheme.to_lowercase(), PathBuf::from(helper))),
+                    _ => error!("Некорректный транспорт {} = '{}', ожидается exec:/путь/к/программе", scheme, value),
+                }
+            }
+        }
+        config
+    }
+}
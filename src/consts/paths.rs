@@ -1,5 +1,19 @@
 
 
+use std::path::{Path, PathBuf};
+
 pub const DB_PATH: &str = "/var/lib/konpac/packages.db";
+pub const PACKAGES_PATH: &str = "/var/lib/konpac/packages";
 pub const TMP_PATH: &str = "/tmp";
-pub const REPOS_FILE: &str = "/etc/konpac/repos";
\ No newline at end of file
+pub const REPOS_FILE: &str = "/etc/konpac/repos";
+pub const CREDENTIALS_FILE: &str = "/etc/konpac/credentials";
+pub const MIRRORS_STATE_FILE: &str = "/var/lib/konpac/mirrors";
+pub const CONFIG_FILE: &str = "/etc/konpac/konpac.conf";
+pub const SYNC_PATH: &str = "/var/lib/konpac/sync";
+pub const CACHE_PATH: &str = "/var/cache/konpac/pkg";
+
+// Путь внутри корня системы: /etc/konpac/repos при корне /mnt становится /mnt/etc/konpac/repos
+pub fn rooted(root: &Path, path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
@@ -0,0 +1,266 @@
+use std::env::consts::ARCH;
+use crate::error::KonpacError;
+use std::path::{Path, PathBuf};
+use log::{error, info};
+use crate::config::Config;
+use crate::consts::paths::{rooted, CONFIG_FILE, CREDENTIALS_FILE, REPOS_FILE};
+use crate::db::{InstalledPackage, LocalDb, RepoPackage, SyncDb};
+use crate::package::autoremove::autoremove;
+use crate::package::backup::{pending_konpacnew, PendingConfig};
+use crate::package::downgrade::downgrade_package;
+use crate::package::install::{install_local, install_requested, InstallOptions};
+use crate::package::preview::{preview_autoremove, preview_install, preview_local, preview_remove, preview_upgrade, DryRun};
+use crate::package::remove::uninstall_package;
+use crate::package::upgrade::upgrade_system;
+use crate::repo::index::sync_index;
+use crate::repo::mirror::{rank_mirrors, Fetcher, MirrorRank};
+use crate::repo::utils::Repository;
+
+// События операции для внешних интерфейсов; подробный ход операции по-прежнему пишется в лог
+#[derive(Debug, Clone)]
+pub enum Event {
+    // Индекс репозитория синхронизирован
+    Synced { repo: String },
+    // Архив пакета загружен в кэш
+    Downloaded { name: String, version: String },
+    // Начата распаковка пакета
+    Installing { name: String, version: String },
+    Installed { name: String, version: String },
+    Removed { name: String, version: String },
+}
+
+// Получатель событий операции; может вызываться из любого потока
+pub type ProgressCallback = Box<dyn Fn(&Event) + Send + Sync>;
+
+// Контекст konpac: корень системы, настройки и база установленных пакетов.
+// Одна база открывается на весь контекст, поэтому операции видят изменения друг друга.
+// Контекст можно передать в другой поток (Send), но соединение SQLite не разделяется между потоками,
+// поэтому он не Sync и futures его методов не Send: их нужно выполнять на current_thread runtime,
+// внутри tokio::task::LocalSet или через block_on, а не через tokio::spawn
+pub struct Konpac {
+    config: Config,
+    db: LocalDb,
+    options: InstallOptions,
+}
+
+impl Konpac {
+    // Контекст для системы с корнем root: настройки, список репозиториев и база берутся внутри него.
+    // Подтверждения по умолчанию не запрашиваются, их включает options_mut().yes = false
+    pub fn open(root: &Path) -> Result<Self, KonpacError> {
+        Ok(Self::with_db(root, LocalDb::open(root)?))
+    }
+
+    // Контекст только для просмотра операций (preview_*): база открывается на чтение и не мигрируется
+    pub fn open_read_only(root: &Path) -> Result<Self, KonpacError> {
+        Ok(Self::with_db(root, LocalDb::open_read_only(root)?))
+    }
+
+    fn with_db(root: &Path, db: LocalDb) -> Self {
+        let config = Config::load(&rooted(root, CONFIG_FILE));
+        let options = InstallOptions {
+            yes: true,
+            arch: ARCH.to_string(),
+            reinstall: false,
+            parallel_downloads: config.parallel_downloads,
+            ignore_pkg: config.ignore_pkg.clone(),
+            root: root.to_path_buf(),
+            repos_file: rooted(root, REPOS_FILE),
+            credentials_file: rooted(root, CREDENTIALS_FILE),
+            progress: None,
+            fetcher: Fetcher::new(config.clone(), root),
+        };
+        Konpac { config, db, options }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.options.root
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn db(&self) -> &LocalDb {
+        &self.db
+    }
+
+    // Параметры операций: подтверждение, архитектура, переустановка
+    pub fn options(&self) -> &InstallOptions {
+        &self.options
+    }
+
+    pub fn options_mut(&mut self) -> &mut InstallOptions {
+        &mut self.options
+    }
+
+    // Получатель событий всех следующих операций
+    pub fn on_progress(&mut self, progress: impl Fn(&Event) + Send + Sync + 'static) {
+        self.options.progress = Some(Box::new(progress));
+    }
+
+    // Конец операции с загрузками: состояние зеркал записывается один раз, в том числе после ошибки
+    fn finish<T>(&self, result: Result<T, KonpacError>) -> Result<T, KonpacError> {
+        self.options.fetcher.save();
+        result
+    }
+
+    // Репозитории из списка контекста под его архитектуру
+    pub fn repos(&self) -> Result<Vec<Repository>, KonpacError> {
+        self.options.repos()
+    }
+
+    // Установка пакета из репозитория по запросу вида "name", "repo/name" или "name>=version"
+    pub async fn install(&self, query: &str) -> Result<(), KonpacError> {
+        self.finish(install_requested(&self.db, query, &self.options).await)
+    }
+
+    // Установка пакета из архива или распакованного каталога пакета
+    pub async fn install_file(&self, path: &Path) -> Result<(), KonpacError> {
+        self.finish(install_local(&self.db, &[path.to_path_buf()], &self.options).await)
+    }
+
+    // Установка нескольких локальных пакетов; зависимости между ними удовлетворяются до обращения к репозиториям
+    pub async fn install_files(&self, paths: &[PathBuf]) -> Result<(), KonpacError> {
+        self.finish(install_local(&self.db, paths, &self.options).await)
+    }
+
+    pub async fn remove(&self, name: &str) -> Result<(), KonpacError> {
+        uninstall_package(&self.db, name.to_string(), &self.options)
+    }
+
+    // Удаление пакетов, установленных как зависимости и больше никому не нужных
+    pub async fn autoremove(&self) -> Result<Vec<String>, KonpacError> {
+        autoremove(&self.db, &self.options)
+    }
+
+    pub async fn upgrade(&self) -> Result<(), KonpacError> {
+        self.finish(upgrade_system(&self.db, &self.options).await)
+    }
+
+    pub async fn downgrade(&self, query: &str) -> Result<(), KonpacError> {
+        self.finish(downgrade_package(&self.db, query, &self.options).await)
+    }
+
+    // Просмотр операций: план без изменений в системе; состояние зеркал тоже не сохраняется
+    pub async fn preview_install(&self, query: &str) -> Result<DryRun, KonpacError> {
+        preview_install(&self.db, &[query.to_string()], &self.options).await
+    }
+
+    pub async fn preview_install_files(&self, paths: &[PathBuf]) -> Result<DryRun, KonpacError> {
+        preview_local(&self.db, paths, &self.options).await
+    }
+
+    pub async fn preview_remove(&self, name: &str) -> Result<DryRun, KonpacError> {
+        preview_remove(&self.db, name, &self.options)
+    }
+
+    pub async fn preview_autoremove(&self) -> Result<DryRun, KonpacError> {
+        preview_autoremove(&self.db, &self.options)
+    }
+
+    pub async fn preview_upgrade(&self) -> Result<DryRun, KonpacError> {
+        preview_upgrade(&self.db, &self.options).await
+    }
+
+    // Синхронизация индексов включенных репозиториев; ошибка, если не удалось хотя бы с одним
+    pub async fn sync(&self) -> Result<Vec<PathBuf>, KonpacError> {
+        let mut synced = Vec::new();
+        let mut failed = Vec::new();
+        for repo in self.repos()?.into_iter().filter(|repo| repo.enabled && repo.serves_arch()) {
+            match sync_index(&self.options.fetcher, &repo).await {
+                Ok(path) => {
+                    info!("Индекс {} синхронизирован", repo.name);
+                    self.options.report(Event::Synced { repo: repo.name.clone() });
+                    synced.push(path);
+                }
+                Err(e) => {
+                    error!("Ошибка синхронизации {}: {}", repo.name, e);
+                    failed.push(repo.name);
+                }
+            }
+        }
+        self.options.fetcher.save();
+        if !failed.is_empty() {
+            return Err(KonpacError::Network(format!("Не удалось синхронизировать: {}", failed.join(", "))));
+        }
+        Ok(synced)
+    }
+
+    // Проверка зеркал репозитория с переупорядочиванием его mirrorlist
+    pub async fn rank_mirrors(&self, repo: &Repository) -> Result<Vec<MirrorRank>, KonpacError> {
+        self.finish(rank_mirrors(&self.options.fetcher, repo).await)
+    }
+
+    // Поиск по части имени в индексах репозиториев: новейшая версия каждого пакета и его репозиторий
+    pub async fn search(&self, term: &str) -> Result<Vec<(String, RepoPackage)>, KonpacError> {
+        self.finish(self.search_indexes(term).await)
+    }
+
+    async fn search_indexes(&self, term: &str) -> Result<Vec<(String, RepoPackage)>, KonpacError> {
+        let mut found = Vec::new();
+        for repo in self.repos()?.into_iter().filter(|repo| repo.enabled && repo.serves_arch()) {
+            let db_path = sync_index(&self.options.fetcher, &repo).await?;
+            found.extend(SyncDb::open(&db_path)?
+                .search(term, &repo.arch)?
+                .into_iter()
+                .filter(|package| repo.allows(&package.name))
+                .map(|package| (repo.name.clone(), package)));
+        }
+        Ok(found)
+    }
+
+    // Установленные пакеты: все или один по имени
+    pub async fn query(&self, name: Option<&str>) -> Result<Vec<InstalledPackage>, KonpacError> {
+        match name {
+            Some(name) => Ok(self.db.installed(name)?.into_iter().collect()),
+            None => self.db.packages(),
+        }
+    }
+
+    // Ожидающие слияния .konpacnew файлы
+    pub fn pending_configs(&self) -> Result<Vec<PendingConfig>, KonpacError> {
+        pending_konpacnew(&self.db, &self.options.root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::consts::paths::{MIRRORS_STATE_FILE, SYNC_PATH};
+    use crate::package::stage::WorkDir;
+    use crate::repo::gen::generate_repo;
+
+    #[test]
+    fn konpac_can_move_between_threads() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Konpac>();
+    }
+
+    #[tokio::test]
+    async fn roots_keep_separate_sync_and_mirror_state() {
+        let work_dir = WorkDir::temp("context-roots");
+        let mut indexes = Vec::new();
+        // Два корня с одноименным репозиторием на разных зеркалах
+        for name in ["a", "b"] {
+            let repo = work_dir.path().join(format!("repo-{}", name));
+            fs::create_dir_all(&repo).unwrap();
+            generate_repo(&repo, false).unwrap();
+            let root = work_dir.path().join(format!("root-{}", name));
+            let repos_file = rooted(&root, REPOS_FILE);
+            fs::create_dir_all(repos_file.parent().unwrap()).unwrap();
+            fs::write(&repos_file, format!("[shared]\nurl = file://{}/\n", repo.display())).unwrap();
+
+            let konpac = Konpac::open(&root).unwrap();
+            let synced = konpac.sync().await.unwrap();
+            let index = rooted(&root, SYNC_PATH).join("shared.db");
+            assert_eq!(synced, std::slice::from_ref(&index));
+            assert!(rooted(&root, SYNC_PATH).join("shared.manifest").exists());
+            assert!(rooted(&root, MIRRORS_STATE_FILE).exists());
+            indexes.push(index);
+        }
+        // Синхронизация второго корня не трогает индекс первого
+        assert_ne!(indexes[0], indexes[1]);
+        assert!(indexes.iter().all(|index| index.exists()));
+    }
+}
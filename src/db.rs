@@ -0,0 +1,732 @@
+use crate::error::KonpacError;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::info;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row, TransactionBehavior};
+use crate::consts::paths::{rooted, DB_PATH};
+use crate::package::utils::{compare_versions, history_action, PackageManifest};
+use crate::repo::download::Expected;
+use crate::repo::index::INDEX_FORMAT;
+
+// Миграции схемы локальной базы пакетов по порядку; номер последней примененной хранится в PRAGMA user_version.
+// Изменения схемы добавляются только в конец списка. Таблицы создаются с IF NOT EXISTS,
+// потому что в базах до появления миграций они уже есть, а user_version равен 0
+const MIGRATIONS: &[&str] = &[
+    // 1: установленные пакеты
+    "CREATE TABLE IF NOT EXISTS packages (
+        name TEXT NOT NULL,
+        version TEXT NOT NULL,
+        path TEXT NOT NULL,
+        PRIMARY KEY (name, version)
+    )",
+    // 2: история версий
+    "CREATE TABLE IF NOT EXISTS history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        time INTEGER NOT NULL,
+        action TEXT NOT NULL,
+        name TEXT NOT NULL,
+        old_version TEXT,
+        new_version TEXT
+    )",
+    // 3: закрепленные пакеты
+    "CREATE TABLE IF NOT EXISTS holds (name TEXT PRIMARY KEY)",
+    // 4: пакеты, установленные только как зависимости (кандидаты для autoremove)
+    "CREATE TABLE IF NOT EXISTS depencies (name TEXT PRIMARY KEY)",
+];
+
+// Сколько ждать, пока другой процесс konpac держит базу
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Установленный пакет: запись локальной базы
+#[derive(Debug, Clone)]
+pub struct InstalledPackage {
+    pub name: String,
+    pub version: String,
+    // Каталог пакета в /var/lib/konpac/packages внутри корня системы, как его видит konpac
+    pub path: String,
+}
+
+// Запись истории версий пакета
+#[derive(Debug)]
+pub struct HistoryEntry {
+    pub time: u64,
+    pub action: String,
+    pub name: String,
+    pub old_version: Option<String>,
+    pub new_version: Option<String>,
+}
+
+// Результат konpac db check
+pub struct DbCheck {
+    pub schema_version: usize,
+    // Ошибки PRAGMA integrity_check
+    pub integrity: Vec<String>,
+    // Записи пакетов, каталог которых отсутствует
+    pub missing: Vec<InstalledPackage>,
+}
+
+impl DbCheck {
+    pub fn is_ok(&self) -> bool {
+        self.integrity.is_empty() && self.missing.is_empty()
+    }
+}
+
+fn schema_version(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0)).map(|version| version as usize)
+}
+
+// Применение недостающих миграций. Версия перечитывается под исключительной блокировкой базы,
+// поэтому два процесса konpac не применят одну миграцию дважды
+fn migrate(conn: &mut Connection) -> Result<(), KonpacError> {
+    let version = schema_version(conn)?;
+    if version > MIGRATIONS.len() {
+        return Err(KonpacError::Conflict(format!("Схема базы пакетов версии {} новее поддерживаемой {}, обновите konpac", version, MIGRATIONS.len())));
+    }
+    if version == MIGRATIONS.len() {
+        return Ok(());
+    }
+    let transaction = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+    let version = schema_version(&transaction)?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Миграция базы пакетов {} -> {}", index, index + 1);
+        transaction.execute_batch(migration)?;
+    }
+    transaction.pragma_update(None, "user_version", MIGRATIONS.len() as i64)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+// Локальная база установленных пакетов системы с корнем root; одно соединение на всю операцию konpac.
+// Каталоги пакетов хранятся путями внутри корня, чтобы база оставалась верной после переноса системы
+// или загрузки в нее, и получают префикс корня при чтении
+pub struct LocalDb {
+    conn: Connection,
+    root: PathBuf,
+}
+
+impl LocalDb {
+    // Открытие базы DB_PATH внутри root с приведением схемы к текущей версии
+    pub fn open(root: &Path) -> Result<Self, KonpacError> {
+        let db_path = rooted(root, DB_PATH);
+        if let Some(parent) = db_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut conn = Connection::open(&db_path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        migrate(&mut conn)?;
+        Ok(LocalDb { conn, root: root.to_path_buf() })
+    }
+
+    // Открытие базы только для чтения, для просмотра операций: ни каталоги, ни схема не меняются.
+    // Без базы система считается пустой; базу старой схемы нужно сначала обновить обычной операцией
+    pub fn open_read_only(root: &Path) -> Result<Self, KonpacError> {
+        let db_path = rooted(root, DB_PATH);
+        if !db_path.exists() {
+            let mut conn = Connection::open_in_memory()?;
+            migrate(&mut conn)?;
+            return Ok(LocalDb { conn, root: root.to_path_buf() });
+        }
+        let conn = Connection::open_with_flags(&db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        let version = schema_version(&conn)?;
+        if version != MIGRATIONS.len() {
+            return Err(KonpacError::Conflict(format!(
+                "Схема базы пакетов {:?} версии {}, а konpac использует версию {}: выполните операцию без --dry-run, чтобы обновить базу, или обновите konpac",
+                db_path, version, MIGRATIONS.len()
+            )));
+        }
+        Ok(LocalDb { conn, root: root.to_path_buf() })
+    }
+
+    fn package_from_row(&self, row: &Row) -> rusqlite::Result<InstalledPackage> {
+        let path: String = row.get(2)?;
+        Ok(InstalledPackage { name: row.get(0)?, version: row.get(1)?, path: rooted(&self.root, path).to_string_lossy().into_owned() })
+    }
+
+    // Изменения внутри f применяются целиком или не применяются вовсе
+    pub fn transaction<T>(&self, f: impl FnOnce(&Self) -> Result<T, KonpacError>) -> Result<T, KonpacError> {
+        self.conn.execute_batch("BEGIN IMMEDIATE")?;
+        match f(self) {
+            Ok(value) => {
+                self.conn.execute_batch("COMMIT")?;
+                Ok(value)
+            }
+            Err(e) => {
+                self.conn.execute_batch("ROLLBACK")?;
+                Err(e)
+            }
+        }
+    }
+
+    // Установленная версия пакета
+    pub fn installed(&self, name: &str) -> Result<Option<InstalledPackage>, KonpacError> {
+        let mut stmt = self.conn.prepare_cached("SELECT name, version, path FROM packages WHERE name = ?1")?;
+        let mut packages = stmt.query_map([name], |row| self.package_from_row(row))?.collect::<Result<Vec<_>, _>>()?;
+        // Несколько версий одного пакета остаются только в поврежденной базе, берется новейшая
+        packages.sort_by(|a, b| compare_versions(&b.version, &a.version));
+        Ok(packages.into_iter().next())
+    }
+
+    pub fn packages(&self) -> Result<Vec<InstalledPackage>, KonpacError> {
+        let mut stmt = self.conn.prepare_cached("SELECT name, version, path FROM packages ORDER BY name")?;
+        let packages = stmt.query_map([], |row| self.package_from_row(row))?.collect::<Result<_, _>>()?;
+        Ok(packages)
+    }
+
+    // Каталог установленного пакета, если он существует
+    pub fn package_dir(&self, name: &str) -> Result<Option<PathBuf>, KonpacError> {
+        Ok(self.installed(name)?.map(|package| PathBuf::from(package.path)).filter(|path| path.exists()))
+    }
+
+    // package_path - каталог пакета внутри корня; в базу записывается путь без префикса корня
+    pub fn add_package(&self, manifest: &PackageManifest, package_path: &Path) -> Result<(), KonpacError> {
+        let stored = Path::new("/").join(package_path.strip_prefix(&self.root).unwrap_or(package_path));
+        self.conn.prepare_cached("INSERT OR REPLACE INTO packages (name, version, path) VALUES (?1, ?2, ?3)")?
+            .execute(params![manifest.name, manifest.version, stored.to_string_lossy()])?;
+        Ok(())
+    }
+
+    pub fn remove_package(&self, name: &str) -> Result<(), KonpacError> {
+        let removed = self.conn.prepare_cached("DELETE FROM packages WHERE name = ?1")?.execute([name])?;
+        if removed == 0 {
+            return Err(KonpacError::NotFound(format!("Пакет '{}' не найден в базе данных", name)));
+        }
+        Ok(())
+    }
+
+    pub fn record_history(&self, name: &str, old_version: Option<&str>, new_version: Option<&str>) -> Result<(), KonpacError> {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.conn.prepare_cached("INSERT INTO history (time, action, name, old_version, new_version) VALUES (?1, ?2, ?3, ?4, ?5)")?
+            .execute(params![time as i64, history_action(old_version, new_version), name, old_version, new_version])?;
+        Ok(())
+    }
+
+    // История всех пакетов или одного пакета, от старых записей к новым
+    pub fn history(&self, name: Option<&str>) -> Result<Vec<HistoryEntry>, KonpacError> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT time, action, name, old_version, new_version FROM history
+             WHERE ?1 IS NULL OR name = ?1 ORDER BY id",
+        )?;
+        let entries = stmt.query_map(params![name], |row| {
+            Ok(HistoryEntry {
+                time: row.get::<_, i64>(0)? as u64,
+                action: row.get(1)?,
+                name: row.get(2)?,
+                old_version: row.get(3)?,
+                new_version: row.get(4)?,
+            })
+        })?.collect::<Result<_, _>>()?;
+        Ok(entries)
+    }
+
+    // Закрепление пакета: транзакции не меняют его версию
+    pub fn hold(&self, name: &str) -> Result<(), KonpacError> {
+        self.conn.prepare_cached("INSERT OR IGNORE INTO holds (name) VALUES (?1)")?.execute([name])?;
+        Ok(())
+    }
+
+    // Снятие закрепления; false, если пакет не был закреплен
+    pub fn unhold(&self, name: &str) -> Result<bool, KonpacError> {
+        Ok(self.conn.prepare_cached("DELETE FROM holds WHERE name = ?1")?.execute([name])? > 0)
+    }
+
+    pub fn held(&self) -> Result<Vec<String>, KonpacError> {
+        let mut stmt = self.conn.prepare_cached("SELECT name FROM holds ORDER BY name")?;
+        let names = stmt.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+        Ok(names)
+    }
+
+    pub fn is_held(&self, name: &str) -> Result<bool, KonpacError> {
+        Ok(self.conn.prepare_cached("SELECT 1 FROM holds WHERE name = ?1")?.exists([name])?)
+    }
+
+    // Причина установки: только как зависимость или по запросу пользователя
+    pub fn set_depency(&self, name: &str, depency: bool) -> Result<(), KonpacError> {
+        let sql = if depency {
+            "INSERT OR IGNORE INTO depencies (name) VALUES (?1)"
+        } else {
+            "DELETE FROM depencies WHERE name = ?1"
+        };
+        self.conn.prepare_cached(sql)?.execute([name])?;
+        Ok(())
+    }
+
+    // Установленные пакеты, поставленные только как зависимости
+    pub fn depencies(&self) -> Result<Vec<String>, KonpacError> {
+        let mut stmt = self.conn.prepare_cached("SELECT name FROM depencies WHERE name IN (SELECT name FROM packages) ORDER BY name")?;
+        let names = stmt.query_map([], |row| row.get(0))?.collect::<Result<_, _>>()?;
+        Ok(names)
+    }
+
+    // Проверка целостности базы и соответствия записей каталогам пакетов
+    pub fn check(&self) -> Result<DbCheck, KonpacError> {
+        let integrity = self.conn.prepare("PRAGMA integrity_check")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|line| line != "ok")
+            .collect();
+        let missing = self.packages()?
+            .into_iter()
+            .filter(|package| !Path::new(&package.path).exists())
+            .collect();
+        Ok(DbCheck { schema_version: schema_version(&self.conn)?, integrity, missing })
+    }
+
+    // Удаление записей пакетов без каталога одной транзакцией
+    pub fn remove_missing(&self, missing: &[InstalledPackage]) -> Result<usize, KonpacError> {
+        self.transaction(|db| {
+            let mut removed = 0;
+            for package in missing {
+                removed += db.conn.prepare_cached("DELETE FROM packages WHERE name = ?1 AND version = ?2")?
+                    .execute(params![package.name, package.version])?;
+            }
+            Ok(removed)
+        })
+    }
+}
+
+// Пакет из индекса репозитория
+#[derive(Debug, Clone)]
+pub struct RepoPackage {
+    pub name: String,    // Имя пакета
+    pub version: String, // Версия пакета
+    pub url: String,     // URL для скачивания пакета
+    pub arch: String,    // Архитектура пакета (или "any")
+    pub size: Option<u64>,      // Размер архива, если указан в индексе
+    pub sha256: Option<String>  // sha256 архива, если указан в индексе
+}
+
+impl RepoPackage {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(RepoPackage {
+            name: row.get(0)?,
+            version: row.get(1)?,
+            url: row.get(2)?,
+            arch: row.get(3)?,
+            size: row.get::<_, Option<i64>>(4)?.map(|size| size as u64),
+            sha256: row.get(5)?
+        })
+    }
+
+    // Что ожидается от загруженного архива
+    pub fn expected(&self) -> Expected {
+        Expected { size: self.size, sha256: self.sha256.clone(), max_size: None }
+    }
+}
+
+// Бинарная разница между двумя версиями пакета из индекса
+#[derive(Debug, Clone)]
+pub struct DeltaEntry {
+    pub name: String,
+    pub arch: String,
+    pub from_version: String,
+    // sha256 архива, к которому применяется разница
+    pub from_sha256: String,
+    pub to_version: String,
+    pub url: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl DeltaEntry {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(DeltaEntry {
+            name: row.get(0)?,
+            arch: row.get(1)?,
+            from_version: row.get(2)?,
+            from_sha256: row.get(3)?,
+            to_version: row.get(4)?,
+            url: row.get(5)?,
+            size: row.get::<_, i64>(6)? as u64,
+            sha256: row.get(7)?,
+        })
+    }
+
+    pub fn expected(&self) -> Expected {
+        Expected { size: Some(self.size), sha256: Some(self.sha256.clone()), max_size: None }
+    }
+}
+
+// Схема индекса репозитория, который пишут konpac --gen-repo и konpac repo
+const REPO_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS packages (
+        name TEXT NOT NULL,
+        version TEXT NOT NULL,
+        path TEXT NOT NULL,
+        arch TEXT NOT NULL DEFAULT 'any',
+        size INTEGER,
+        sha256 TEXT,
+        PRIMARY KEY (name, version, arch)
+    );
+    CREATE TABLE IF NOT EXISTS deltas (
+        name TEXT NOT NULL,
+        arch TEXT NOT NULL,
+        from_version TEXT NOT NULL,
+        from_sha256 TEXT NOT NULL,
+        to_version TEXT NOT NULL,
+        path TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        sha256 TEXT NOT NULL,
+        PRIMARY KEY (name, arch, from_version, to_version)
+    );
+    CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+";
+
+// Колонки packages, которых нет в индексах старых версий konpac
+const REPO_COLUMNS: &[(&str, &str)] = &[("arch", "TEXT NOT NULL DEFAULT 'any'"), ("size", "INTEGER"), ("sha256", "TEXT")];
+
+fn has_table(conn: &Connection, table: &str) -> rusqlite::Result<bool> {
+    conn.prepare_cached("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1")?.exists([table])
+}
+
+fn has_column(conn: &Connection, column: &str) -> rusqlite::Result<bool> {
+    conn.prepare_cached("SELECT 1 FROM pragma_table_info('packages') WHERE name = ?1")?.exists([column])
+}
+
+// Колонка или замена для старых индексов, где ее нет
+fn column_or(conn: &Connection, column: &'static str, fallback: &'static str) -> rusqlite::Result<&'static str> {
+    Ok(if has_column(conn, column)? { column } else { fallback })
+}
+
+// Пакеты индекса по условию, которое получает имя колонки архитектуры; по имени и от новой версии к старой.
+// В старых индексах нет arch (такие пакеты считаются "any"), size и sha256
+fn select_packages(conn: &Connection, condition: impl Fn(&str) -> String, params: impl rusqlite::Params) -> Result<Vec<RepoPackage>, KonpacError> {
+    // Индекс без таблицы packages считается пустым
+    if !has_table(conn, "packages")? {
+        return Ok(Vec::new());
+    }
+    let arch_col = column_or(conn, "arch", "'any'")?;
+    let size_col = column_or(conn, "size", "NULL")?;
+    let sha_col = column_or(conn, "sha256", "NULL")?;
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT name, version, path, {arch_col}, {size_col}, {sha_col} FROM packages WHERE {}",
+        condition(arch_col)
+    ))?;
+    let mut packages = stmt.query_map(params, RepoPackage::from_row)?.collect::<Result<Vec<_>, _>>()?;
+    // Версии сравниваются по частям, а не как строки: 1.10 новее 1.9
+    packages.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| compare_versions(&b.version, &a.version)));
+    Ok(packages)
+}
+
+// Разницы индекса по условию, от меньшей к большей. В индексах без таблицы deltas разниц нет
+fn select_deltas(conn: &Connection, condition: &str, params: impl rusqlite::Params) -> Result<Vec<DeltaEntry>, KonpacError> {
+    if !has_table(conn, "deltas")? {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT name, arch, from_version, from_sha256, to_version, path, size, sha256 FROM deltas
+         WHERE {condition} ORDER BY size"
+    ))?;
+    let deltas = stmt.query_map(params, DeltaEntry::from_row)?.collect::<Result<_, _>>()?;
+    Ok(deltas)
+}
+
+// Версия формата и время генерации, записанные внутри индекса
+fn index_stamp(conn: &Connection) -> Result<Option<(u32, u64)>, KonpacError> {
+    if !has_table(conn, "meta")? {
+        return Ok(None);
+    }
+    let value = |key: &str| -> rusqlite::Result<Option<String>> {
+        conn.query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| row.get(0)).optional()
+    };
+    match (value("format")?, value("generated")?) {
+        (Some(format), Some(generated)) => Ok(Some((format.parse()?, generated.parse()?))),
+        _ => Ok(None),
+    }
+}
+
+// Синхронизированный индекс репозитория, только для чтения
+pub struct SyncDb {
+    conn: Connection,
+}
+
+impl SyncDb {
+    pub fn open(db_path: &Path) -> Result<Self, KonpacError> {
+        // Без этой проверки SQLite сообщил бы лишь о невозможности открыть файл
+        if !db_path.exists() {
+            return Err(KonpacError::NotFound(format!("Индекс {:?} не найден", db_path)));
+        }
+        let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(SyncDb { conn })
+    }
+
+    // Все версии пакета под нужную архитектуру, от новой к старой
+    pub fn packages(&self, name: &str, arch: &str) -> Result<Vec<RepoPackage>, KonpacError> {
+        select_packages(&self.conn, |arch_col| format!("name = ?1 AND {arch_col} IN (?2, 'any')"), [name, arch])
+    }
+
+    // Новейшие версии пакетов, в имени которых есть term
+    pub fn search(&self, term: &str, arch: &str) -> Result<Vec<RepoPackage>, KonpacError> {
+        let mut packages = select_packages(&self.conn, |arch_col| format!("instr(name, ?1) > 0 AND {arch_col} IN (?2, 'any')"), [term, arch])?;
+        packages.dedup_by(|a, b| a.name == b.name);
+        Ok(packages)
+    }
+
+    // Все пакеты индекса всех архитектур
+    pub fn all_packages(&self) -> Result<Vec<RepoPackage>, KonpacError> {
+        select_packages(&self.conn, |_| "1".to_string(), [])
+    }
+
+    // Разницы, из которых собирается архив пакета
+    pub fn deltas(&self, package: &RepoPackage) -> Result<Vec<DeltaEntry>, KonpacError> {
+        select_deltas(&self.conn, "name = ?1 AND arch = ?2 AND to_version = ?3", [&package.name, &package.arch, &package.version])
+    }
+
+    pub fn all_deltas(&self) -> Result<Vec<DeltaEntry>, KonpacError> {
+        select_deltas(&self.conn, "1", [])
+    }
+
+    // Версия формата и время генерации индекса
+    pub fn stamp(&self) -> Result<Option<(u32, u64)>, KonpacError> {
+        index_stamp(&self.conn)
+    }
+}
+
+// Индекс репозитория на стороне сервера, открытый на запись
+pub struct RepoDb {
+    conn: Connection,
+}
+
+impl RepoDb {
+    // Открытие или создание индекса; в старых индексах недостающие колонки добавляются
+    pub fn open(db_path: &Path) -> Result<Self, KonpacError> {
+        let conn = Connection::open(db_path)?;
+        conn.execute_batch(REPO_SCHEMA)?;
+        for (column, definition) in REPO_COLUMNS {
+            if !has_column(&conn, column)? {
+                conn.execute_batch(&format!("ALTER TABLE packages ADD COLUMN {} {}", column, definition))?;
+            }
+        }
+        Ok(RepoDb { conn })
+    }
+
+    // Запись с тем же именем, версией и архитектурой заменяется
+    pub fn add_package(&self, package: &RepoPackage) -> Result<(), KonpacError> {
+        self.conn.prepare_cached(
+            "INSERT OR REPLACE INTO packages (name, version, path, arch, size, sha256)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?.execute(params![
+            package.name,
+            package.version,
+            package.url,
+            package.arch,
+            package.size.map(|size| size as i64),
+            package.sha256,
+        ])?;
+        Ok(())
+    }
+
+    // Удаление всех версий пакета и его разниц; число удаленных версий
+    pub fn remove_package(&self, name: &str) -> Result<usize, KonpacError> {
+        let removed = self.conn.prepare_cached("DELETE FROM packages WHERE name = ?1")?.execute([name])?;
+        self.conn.prepare_cached("DELETE FROM deltas WHERE name = ?1")?.execute([name])?;
+        Ok(removed)
+    }
+
+    pub fn remove_version(&self, package: &RepoPackage) -> Result<(), KonpacError> {
+        self.conn.prepare_cached("DELETE FROM packages WHERE name = ?1 AND version = ?2 AND arch = ?3")?
+            .execute(params![package.name, package.version, package.arch])?;
+        Ok(())
+    }
+
+    pub fn packages(&self) -> Result<Vec<RepoPackage>, KonpacError> {
+        select_packages(&self.conn, |_| "1".to_string(), [])
+    }
+
+    pub fn add_delta(&self, delta: &DeltaEntry) -> Result<(), KonpacError> {
+        self.conn.prepare_cached(
+            "INSERT OR REPLACE INTO deltas (name, arch, from_version, from_sha256, to_version, path, size, sha256)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?.execute(params![
+            delta.name,
+            delta.arch,
+            delta.from_version,
+            delta.from_sha256,
+            delta.to_version,
+            delta.url,
+            delta.size as i64,
+            delta.sha256,
+        ])?;
+        Ok(())
+    }
+
+    pub fn remove_delta(&self, delta: &DeltaEntry) -> Result<(), KonpacError> {
+        self.conn.prepare_cached("DELETE FROM deltas WHERE name = ?1 AND arch = ?2 AND from_version = ?3 AND to_version = ?4")?
+            .execute(params![delta.name, delta.arch, delta.from_version, delta.to_version])?;
+        Ok(())
+    }
+
+    pub fn deltas(&self) -> Result<Vec<DeltaEntry>, KonpacError> {
+        select_deltas(&self.conn, "1", [])
+    }
+
+    // Новая отметка индекса; время генерации всегда больше предыдущего и уже опубликованного (published),
+    // даже в пределах одной секунды или если часы сервера отстают
+    pub fn stamp(&self, published: u64) -> Result<u64, KonpacError> {
+        let previous = index_stamp(&self.conn)?.map(|(_, generated)| generated).unwrap_or(0).max(published);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let generated = now.max(previous + 1);
+        let mut stmt = self.conn.prepare_cached("INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)")?;
+        stmt.execute(["format", &INDEX_FORMAT.to_string()])?;
+        stmt.execute(["generated", &generated.to_string()])?;
+        Ok(generated)
+    }
+
+    // Закрытие с проверкой, что все записано на диск
+    pub fn close(self) -> Result<(), KonpacError> {
+        self.conn.close().map_err(|(_, e)| e)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::stage::WorkDir;
+
+    fn manifest(name: &str, version: &str) -> PackageManifest {
+        PackageManifest { name: name.to_string(), version: version.to_string(), arch: "any".to_string(), depens: Vec::new(), backup: Vec::new() }
+    }
+
+    #[test]
+    fn migrations_upgrade_database_without_user_version() {
+        let work_dir = WorkDir::temp("db-migrate");
+        let root = work_dir.path();
+        let db_path = rooted(root, DB_PATH);
+        fs::create_dir_all(db_path.parent().unwrap()).unwrap();
+        // База до появления миграций: только таблица пакетов и user_version 0
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute("INSERT INTO packages (name, version, path) VALUES ('old', '1.0', '/var/lib/konpac/packages/old-1.0')", []).unwrap();
+        drop(conn);
+
+        let db = LocalDb::open(root).unwrap();
+        assert_eq!(schema_version(&db.conn).unwrap(), MIGRATIONS.len());
+        assert_eq!(db.installed("old").unwrap().unwrap().version, "1.0");
+        db.hold("old").unwrap();
+        db.record_history("old", Some("1.0"), None).unwrap();
+        drop(db);
+
+        // Повторное открытие ничего не применяет заново
+        let db = LocalDb::open(root).unwrap();
+        assert_eq!(schema_version(&db.conn).unwrap(), MIGRATIONS.len());
+        assert!(db.is_held("old").unwrap());
+        drop(db);
+
+        // Схема новее поддерживаемой не открывается
+        let conn = Connection::open(&db_path).unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() as i64 + 1).unwrap();
+        drop(conn);
+        assert!(matches!(LocalDb::open(root), Err(KonpacError::Conflict(_))));
+    }
+
+    #[test]
+    fn migrations_keep_populated_v0_database() {
+        let work_dir = WorkDir::temp("db-v0");
+        let root = work_dir.path();
+        let db_path = rooted(root, DB_PATH);
+        fs::create_dir_all(db_path.parent().unwrap()).unwrap();
+        // База версии 0, которую таблицы создавали по мере надобности: лишний старый столбец в packages,
+        // история уже есть, holds и depencies еще нет
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch("
+            CREATE TABLE packages (name TEXT NOT NULL, version TEXT NOT NULL, path TEXT NOT NULL, installed_by TEXT, PRIMARY KEY (name, version));
+            INSERT INTO packages VALUES ('lmms', '1.2.2', '/var/lib/konpac/packages/lmms-1.2.2', 'konpac 0.1');
+            INSERT INTO packages (name, version, path) VALUES ('zlib', '1.3', '/var/lib/konpac/packages/zlib-1.3');
+            CREATE TABLE history (id INTEGER PRIMARY KEY AUTOINCREMENT, time INTEGER NOT NULL, action TEXT NOT NULL, name TEXT NOT NULL, old_version TEXT, new_version TEXT);
+            INSERT INTO history (time, action, name, old_version, new_version) VALUES (1, 'install', 'lmms', NULL, '1.2.2');
+        ").unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+        drop(conn);
+
+        let db = LocalDb::open(root).unwrap();
+        assert_eq!(schema_version(&db.conn).unwrap(), MIGRATIONS.len());
+        let names: Vec<String> = db.packages().unwrap().into_iter().map(|package| package.name).collect();
+        assert_eq!(names, ["lmms", "zlib"]);
+        assert_eq!(db.history(Some("lmms")).unwrap().len(), 1);
+        // Новые таблицы созданы и работают рядом со старыми данными
+        db.hold("lmms").unwrap();
+        db.set_depency("zlib", true).unwrap();
+        assert_eq!(db.depencies().unwrap(), ["zlib"]);
+        db.add_package(&manifest("lmms", "1.3.0"), &rooted(root, "/var/lib/konpac/packages/lmms-1.3.0")).unwrap();
+        assert_eq!(db.installed("lmms").unwrap().unwrap().version, "1.3.0");
+        assert!(db.check().unwrap().integrity.is_empty());
+    }
+
+    #[test]
+    fn read_only_open_leaves_database_untouched() {
+        let work_dir = WorkDir::temp("db-read-only");
+        let root = work_dir.path();
+        let db_path = rooted(root, DB_PATH);
+
+        // Без базы система пуста, а каталог базы не создается
+        let db = LocalDb::open_read_only(root).unwrap();
+        assert!(db.packages().unwrap().is_empty());
+        assert!(!db_path.parent().unwrap().exists());
+        drop(db);
+
+        // База старой схемы не мигрируется
+        fs::create_dir_all(db_path.parent().unwrap()).unwrap();
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        drop(conn);
+        assert!(matches!(LocalDb::open_read_only(root), Err(KonpacError::Conflict(_))));
+        let conn = Connection::open(&db_path).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+        drop(conn);
+
+        // Текущая схема читается, но не записывается
+        LocalDb::open(root).unwrap().add_package(&manifest("pkg", "1.0"), &rooted(root, "/var/lib/konpac/packages/pkg-1.0")).unwrap();
+        let db = LocalDb::open_read_only(root).unwrap();
+        assert_eq!(db.installed("pkg").unwrap().unwrap().version, "1.0");
+        assert!(db.hold("pkg").is_err());
+    }
+
+    #[test]
+    fn holds_history_and_root_relative_paths() {
+        let work_dir = WorkDir::temp("db-state");
+        let root = work_dir.path();
+        let db = LocalDb::open(root).unwrap();
+
+        db.hold("kernel").unwrap();
+        db.hold("kernel").unwrap();
+        db.hold("glibc").unwrap();
+        assert_eq!(db.held().unwrap(), ["glibc", "kernel"]);
+        assert!(db.unhold("kernel").unwrap());
+        assert!(!db.unhold("kernel").unwrap());
+        assert!(!db.is_held("kernel").unwrap());
+
+        db.record_history("pkg", None, Some("1.0")).unwrap();
+        db.record_history("pkg", Some("1.0"), Some("2.0")).unwrap();
+        db.record_history("other", None, Some("1.0")).unwrap();
+        db.record_history("pkg", Some("2.0"), Some("1.5")).unwrap();
+        db.record_history("pkg", Some("1.5"), None).unwrap();
+        let actions: Vec<String> = db.history(Some("pkg")).unwrap().into_iter().map(|entry| entry.action).collect();
+        assert_eq!(actions, ["install", "upgrade", "downgrade", "remove"]);
+        assert_eq!(db.history(None).unwrap().len(), 5);
+
+        // В базе путь внутри корня, при чтении он получает префикс корня
+        let package_path = rooted(root, "/var/lib/konpac/packages/pkg-1.0");
+        db.add_package(&manifest("pkg", "1.0"), &package_path).unwrap();
+        let stored: String = db.conn.query_row("SELECT path FROM packages WHERE name = 'pkg'", [], |row| row.get(0)).unwrap();
+        assert_eq!(stored, "/var/lib/konpac/packages/pkg-1.0");
+        assert_eq!(PathBuf::from(db.installed("pkg").unwrap().unwrap().path), package_path);
+
+        // Метка зависимости видна, только пока пакет установлен
+        db.set_depency("pkg", true).unwrap();
+        db.set_depency("gone", true).unwrap();
+        assert_eq!(db.depencies().unwrap(), ["pkg"]);
+        db.set_depency("pkg", false).unwrap();
+        assert!(db.depencies().unwrap().is_empty());
+
+        // Неудачная транзакция не оставляет изменений
+        let result: Result<(), KonpacError> = db.transaction(|db| {
+            db.remove_package("pkg")?;
+            db.remove_package("missing")
+        });
+        assert!(matches!(result, Err(KonpacError::NotFound(_))));
+        assert!(db.installed("pkg").unwrap().is_some());
+    }
+}
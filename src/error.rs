@@ -0,0 +1,200 @@
+use std::io;
+use thiserror::Error;
+
+// Ошибки konpac; по виду ошибки консольная утилита выбирает код завершения
+#[derive(Debug, Error)]
+pub enum KonpacError {
+    // Пакет, версия, репозиторий или файл не найдены
+    #[error("{0}")]
+    NotFound(String),
+    // Операция противоречит состоянию системы: закрепленный пакет, чужая архитектура, откат индекса
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    DependencyUnsatisfied(String),
+    // Размер или sha256 не совпадает с индексом
+    #[error("{0}")]
+    Checksum(String),
+    #[error("{0}")]
+    Signature(String),
+    #[error("{0}")]
+    Network(String),
+    #[error("{0}")]
+    Db(String),
+    // Скрипт пакета не запустился или завершился с ошибкой
+    #[error("{0}")]
+    Script(String),
+    #[error("{0}")]
+    Permission(String),
+    // База пакетов занята другим процессом konpac
+    #[error("{0}")]
+    Lock(String),
+    // Некорректные входные данные: запрос, манифест, архив, индекс или настройки
+    #[error("{0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Io(io::Error),
+}
+
+impl KonpacError {
+    // Код завершения konpac; 1 остается для ошибок без вида, например итогов с частичными сбоями
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            KonpacError::NotFound(_) => 2,
+            KonpacError::Conflict(_) => 3,
+            KonpacError::DependencyUnsatisfied(_) => 4,
+            KonpacError::Checksum(_) => 5,
+            KonpacError::Signature(_) => 6,
+            KonpacError::Network(_) => 7,
+            KonpacError::Db(_) => 8,
+            KonpacError::Script(_) => 9,
+            KonpacError::Permission(_) => 10,
+            KonpacError::Lock(_) => 11,
+            KonpacError::Invalid(_) => 12,
+            KonpacError::Io(_) => 13,
+        }
+    }
+
+    // Та же ошибка с пояснением, при какой операции она произошла
+    pub fn context(self, context: impl std::fmt::Display) -> Self {
+        let message = |e: String| format!("{}: {}", context, e);
+        match self {
+            KonpacError::NotFound(e) => KonpacError::NotFound(message(e)),
+            KonpacError::Conflict(e) => KonpacError::Conflict(message(e)),
+            KonpacError::DependencyUnsatisfied(e) => KonpacError::DependencyUnsatisfied(message(e)),
+            KonpacError::Checksum(e) => KonpacError::Checksum(message(e)),
+            KonpacError::Signature(e) => KonpacError::Signature(message(e)),
+            KonpacError::Network(e) => KonpacError::Network(message(e)),
+            KonpacError::Db(e) => KonpacError::Db(message(e)),
+            KonpacError::Script(e) => KonpacError::Script(message(e)),
+            KonpacError::Permission(e) => KonpacError::Permission(message(e)),
+            KonpacError::Lock(e) => KonpacError::Lock(message(e)),
+            KonpacError::Invalid(e) => KonpacError::Invalid(message(e)),
+            KonpacError::Io(e) => KonpacError::Io(io::Error::new(e.kind(), message(e.to_string()))),
+        }
+    }
+
+    // Вид ошибки для сообщений пользователю
+    pub fn kind(&self) -> &'static str {
+        match self {
+            KonpacError::NotFound(_) => "не найдено",
+            KonpacError::Conflict(_) => "конфликт",
+            KonpacError::DependencyUnsatisfied(_) => "зависимость не удовлетворена",
+            KonpacError::Checksum(_) => "контрольная сумма",
+            KonpacError::Signature(_) => "подпись",
+            KonpacError::Network(_) => "сеть",
+            KonpacError::Db(_) => "база данных",
+            KonpacError::Script(_) => "скрипт пакета",
+            KonpacError::Permission(_) => "недостаточно прав",
+            KonpacError::Lock(_) => "блокировка",
+            KonpacError::Invalid(_) => "некорректные данные",
+            KonpacError::Io(_) => "ввод-вывод",
+        }
+    }
+}
+
+impl From<io::Error> for KonpacError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => KonpacError::Permission(e.to_string()),
+            io::ErrorKind::NotFound => KonpacError::NotFound(e.to_string()),
+            _ => KonpacError::Io(e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for KonpacError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked) => KonpacError::Lock(e.to_string()),
+            Some(rusqlite::ErrorCode::PermissionDenied | rusqlite::ErrorCode::ReadOnly) => KonpacError::Permission(e.to_string()),
+            _ => KonpacError::Db(e.to_string()),
+        }
+    }
+}
+
+impl From<reqwest::Error> for KonpacError {
+    fn from(e: reqwest::Error) -> Self {
+        KonpacError::Network(e.to_string())
+    }
+}
+
+impl From<walkdir::Error> for KonpacError {
+    fn from(e: walkdir::Error) -> Self {
+        KonpacError::from(io::Error::from(e))
+    }
+}
+
+impl From<ini::Error> for KonpacError {
+    fn from(e: ini::Error) -> Self {
+        match e {
+            ini::Error::Io(e) => KonpacError::from(e),
+            ini::Error::Parse(e) => KonpacError::Invalid(e.to_string()),
+        }
+    }
+}
+
+// Ошибки разбора входных данных
+macro_rules! invalid_from {
+    ($($error:ty),*) => {
+        $(impl From<$error> for KonpacError {
+            fn from(e: $error) -> Self {
+                KonpacError::Invalid(e.to_string())
+            }
+        })*
+    };
+}
+
+invalid_from!(
+    url::ParseError,
+    ini::ParseError,
+    std::num::ParseIntError,
+    glob::PatternError,
+    reqwest::header::InvalidHeaderName,
+    reqwest::header::InvalidHeaderValue
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_kind_has_its_own_exit_code() {
+        let errors = [
+            (KonpacError::NotFound("x".to_string()), 2),
+            (KonpacError::Conflict("x".to_string()), 3),
+            (KonpacError::DependencyUnsatisfied("x".to_string()), 4),
+            (KonpacError::Checksum("x".to_string()), 5),
+            (KonpacError::Signature("x".to_string()), 6),
+            (KonpacError::Network("x".to_string()), 7),
+            (KonpacError::Db("x".to_string()), 8),
+            (KonpacError::Script("x".to_string()), 9),
+            (KonpacError::Permission("x".to_string()), 10),
+            (KonpacError::Lock("x".to_string()), 11),
+            (KonpacError::Invalid("x".to_string()), 12),
+            (KonpacError::Io(io::Error::other("x")), 13),
+        ];
+        for (error, code) in errors {
+            assert_eq!(error.exit_code(), code, "{:?}", error);
+            // Пояснение меняет сообщение, но не вид ошибки
+            let kind = error.kind();
+            let error = error.context("операция");
+            assert_eq!((error.exit_code(), error.kind()), (code, kind));
+            assert_eq!(error.to_string(), "операция: x");
+        }
+    }
+
+    #[test]
+    fn conversions_pick_kind() {
+        let error = KonpacError::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert_eq!(error.exit_code(), 10);
+        assert_eq!(KonpacError::from(io::Error::from(io::ErrorKind::NotFound)).exit_code(), 2);
+        assert_eq!(KonpacError::from(io::Error::from(io::ErrorKind::UnexpectedEof)).exit_code(), 13);
+
+        let busy = rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY), None);
+        assert!(matches!(KonpacError::from(busy), KonpacError::Lock(_)));
+        let read_only = rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_READONLY), None);
+        assert!(matches!(KonpacError::from(read_only), KonpacError::Permission(_)));
+        assert!(matches!(KonpacError::from(rusqlite::Error::QueryReturnedNoRows), KonpacError::Db(_)));
+    }
+}
@@ -0,0 +1,12 @@
+// Библиотека konpac: установка, удаление и обновление пакетов, индексы и репозитории.
+// Точка входа - контекст Konpac; консольная утилита konpac построена на нем же
+pub mod config;
+pub mod consts;
+pub mod context;
+pub mod db;
+pub mod error;
+pub mod package;
+pub mod repo;
+
+pub use context::{Event, Konpac};
+pub use error::KonpacError;
@@ -1,14 +1,17 @@
-mod package; // Подключаем модуль package
-mod repo;    // Подключаем модуль repo
-mod consts;
-// Импортируем необходимые функции и структуры
-use package::install::{install_package_from_file, install_from_repo};
-use package::remove::uninstall_package; // Функция для удаления пакета
-use package::utils::is_elevated;       // Функция для проверки прав администратора
-use repo::gen::generate_repo;          // Функция для генерации репозитория
-use clap::Parser;          // Библиотека для обработки аргументов командной строки
-use std::path::Path;        // Работа с путями
-use repo::utils::get_repos;            // Функция для получения репозиториев
+// Консольная утилита поверх библиотеки konpac
+use konpac::{Konpac, KonpacError};      // Контекст операций с системой и его ошибки
+use konpac::package::utils::is_elevated; // Права администратора
+use konpac::package::cache::{clean_cache, CleanPolicy}; // Очистка кэша пакетов
+use konpac::repo::gen::{add_packages, generate_repo, preview_prune, prune_repo, remove_package, IndexSummary}; // Генерация и изменение индекса репозитория
+use konpac::repo::serve::serve_repo;           // Раздача репозитория по HTTP
+use clap::{ArgGroup, Parser, Subcommand}; // Библиотека для обработки аргументов командной строки
+use konpac::package::preview::DryRun;         // План операции для --dry-run
+use std::path::{Path, PathBuf}; // Работа с путями
+use std::time::Duration;    // Период проверки изменений
+use std::env::consts::ARCH; // Архитектура хоста
+use konpac::repo::utils::get_repos;            // Функция для получения репозиториев
+use konpac::consts::paths::{CREDENTIALS_FILE, REPOS_FILE}; // Список репозиториев и учетные данные
+use konpac::db::LocalDb; // База установленных пакетов
 use log::{info, error};    // Логирование
 use pretty_env_logger::formatted_builder; // Логгер
 use std::sync::Once;
@@ -43,31 +46,462 @@ fn setup_logger() {
 // Определяем структуру для обработки аргументов командной строки
 #[derive(Parser)]
 #[command(version)]
-#[group(required = true, multiple = false)] // Группа аргументов, где можно выбрать только одну операцию
+#[command(group(ArgGroup::new("operation").required(true).multiple(false)))] // Группа аргументов, где можно выбрать только одну операцию
+#[command(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Установить пакет из файла
-    #[arg(short, long)]
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    /// Установить пакет из файла или каталога пакета
+    #[arg(short, long, group = "operation")]
     install: Option<String>,
     
     /// Удалить пакет по имени
-    #[arg(short, long)]
+    #[arg(short, long, group = "operation")]
     remove: Option<String>,
 
     /// Сгенерировать репозиторий из папки с пакетами
-    #[arg(long)]
+    #[arg(long, group = "operation")]
     gen_repo: Option<String>,
 
     /// Получить репозитории
-    #[arg(long)]
+    #[arg(long, group = "operation")]
     get_repo: Option<String>,
 
-    /// Установить пакет из репозитория
-    #[arg(short, long)]
+    /// Установить пакет из репозитория: имя, repo/имя или ограничение версии (pkg=1.2.2, pkg<2.0)
+    #[arg(short, long, group = "operation")]
     download: Option<String>,
 
+    /// Создать бинарные разницы между соседними версиями пакетов (вместе с --gen-repo)
+    #[arg(long, requires = "gen_repo")]
+    deltas: bool,
+
     /// Установить пакет без подтверждения
-    #[arg(long)]
+    #[arg(long, global = true)]
     yes: bool,
+
+    /// Архитектура пакетов (по умолчанию архитектура хоста)
+    #[arg(long, global = true)]
+    arch: Option<String>,
+
+    /// Переустановить пакет, даже если эта версия уже установлена
+    #[arg(long, global = true)]
+    reinstall: bool,
+
+    /// Показать план установки или удаления (-i, -d, -r), ничего не меняя.
+    /// Используются уже синхронизированные индексы (konpac sync) и кэш пакетов; ничего не загружается,
+    /// поэтому файлы и зависимости пакетов не из кэша остаются неизвестными
+    #[arg(long, conflicts_with_all = ["gen_repo", "get_repo"])]
+    dry_run: bool,
+
+    /// Вывести план в JSON
+    #[arg(long, requires = "dry_run")]
+    json: bool,
+}
+
+// Просмотр операции вместо ее выполнения
+#[derive(clap::Args)]
+struct PreviewArgs {
+    /// Показать план операции, ничего не меняя.
+    /// Используются уже синхронизированные индексы (konpac sync) и кэш пакетов; ничего не загружается,
+    /// поэтому файлы и зависимости пакетов не из кэша остаются неизвестными
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Вывести план в JSON
+    #[arg(long, requires = "dry_run")]
+    json: bool,
+}
+
+// Подкоманды konpac
+#[derive(Subcommand)]
+enum Commands {
+    /// Показать новые версии конфигурационных файлов (.konpacnew), ожидающие слияния
+    ConfigDiff,
+
+    /// Работа с репозиториями
+    Repo {
+        #[command(subcommand)]
+        command: RepoCommands,
+    },
+
+    /// Работа с зеркалами репозиториев
+    Mirror {
+        #[command(subcommand)]
+        command: MirrorCommands,
+    },
+
+    /// Установить пакеты из файлов и распакованных каталогов пакетов
+    Install {
+        /// Архивы .kpkg и каталоги с package.yml
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        #[command(flatten)]
+        preview: PreviewArgs,
+    },
+
+    /// Откатить пакет на предыдущую версию из кэша или репозиториев
+    Downgrade {
+        /// Имя пакета
+        package: String,
+    },
+
+    /// Обновить все установленные пакеты
+    Upgrade {
+        #[command(flatten)]
+        preview: PreviewArgs,
+    },
+
+    /// Удалить пакеты, установленные как зависимости и больше никому не нужные
+    Autoremove {
+        #[command(flatten)]
+        preview: PreviewArgs,
+    },
+
+    /// Синхронизировать индексы репозиториев
+    Sync,
+
+    /// Найти пакеты в репозиториях по части имени
+    Search {
+        /// Часть имени пакета
+        term: String,
+    },
+
+    /// Показать установленные пакеты
+    Query {
+        /// Имя пакета (по умолчанию все)
+        package: Option<String>,
+    },
+
+    /// Закрепить пакеты, чтобы транзакции не меняли их версию (без аргументов - список)
+    Hold {
+        /// Имена пакетов
+        packages: Vec<String>,
+    },
+
+    /// Снять закрепление пакетов
+    Unhold {
+        /// Имена пакетов
+        #[arg(required = true)]
+        packages: Vec<String>,
+    },
+
+    /// Показать историю установок, обновлений и откатов
+    History {
+        /// Имя пакета (по умолчанию все)
+        package: Option<String>,
+    },
+
+    /// Работа с кэшем загруженных пакетов
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+
+    /// Работа с базой установленных пакетов
+    Db {
+        #[command(subcommand)]
+        command: DbCommands,
+    },
+}
+
+// Подкоманды konpac db
+#[derive(Subcommand)]
+enum DbCommands {
+    /// Проверить целостность базы и найти записи пакетов без каталога
+    Check,
+}
+
+// Подкоманды konpac repo
+#[derive(Subcommand)]
+enum RepoCommands {
+    /// Показать действующую конфигурацию репозиториев
+    List,
+
+    /// Добавить архивы в индекс репозитория
+    Add {
+        /// Путь к packages.db
+        db: PathBuf,
+
+        /// Архивы пакетов
+        #[arg(required = true)]
+        packages: Vec<PathBuf>,
+    },
+
+    /// Удалить все версии пакета из индекса репозитория
+    Remove {
+        /// Путь к packages.db
+        db: PathBuf,
+
+        /// Имя пакета
+        name: String,
+    },
+
+    /// Удалить устаревшие версии пакетов вместе с архивами
+    Prune {
+        /// Путь к packages.db
+        db: PathBuf,
+
+        /// Сколько новейших версий каждого пакета оставить
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+        keep: u64,
+
+        #[command(flatten)]
+        preview: PreviewArgs,
+    },
+
+    /// Раздать каталог репозитория по HTTP
+    Serve {
+        /// Каталог с пакетами и packages.db
+        dir: PathBuf,
+
+        /// Адрес и порт для входящих соединений
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        bind: String,
+
+        /// Собрать отсутствующий индекс при запуске и пересобирать его при изменении пакетов в каталоге
+        #[arg(long)]
+        watch: bool,
+
+        /// Период проверки изменений в секундах
+        #[arg(long, default_value_t = 5, requires = "watch")]
+        interval: u64,
+
+        /// Создавать при пересборке бинарные разницы между версиями
+        #[arg(long, requires = "watch")]
+        deltas: bool,
+    },
+}
+
+// Подкоманды konpac cache
+#[derive(Subcommand)]
+enum CacheCommands {
+    /// Удалить старые версии пакетов из кэша
+    Clean {
+        /// Сколько новейших версий каждого пакета оставить
+        #[arg(long, default_value_t = 3)]
+        keep: usize,
+
+        /// Оставить только установленные версии
+        #[arg(long, conflicts_with = "keep")]
+        uninstalled: bool,
+    },
+}
+
+// Подкоманды konpac mirror
+#[derive(Subcommand)]
+enum MirrorCommands {
+    /// Проверить зеркала по задержке и актуальности и переупорядочить mirrorlist
+    Rank {
+        /// Имя репозитория (по умолчанию все)
+        repo: Option<String>,
+    },
+}
+
+// Проверка зеркал и вывод их в новом порядке
+async fn rank_repo_mirrors(konpac: &Konpac, repo_name: Option<String>) -> Result<(), KonpacError> {
+    let repositories: Vec<_> = konpac.repos()?
+        .into_iter()
+        .filter(|repo| repo_name.as_ref().is_none_or(|name| &repo.name == name))
+        .collect();
+    if repositories.is_empty() {
+        return Err(KonpacError::NotFound(format!("Репозиторий {} не найден", repo_name.unwrap_or_default())));
+    }
+    for repo in repositories {
+        info!("Проверка зеркал {}: Подготовка", repo.name);
+        println!("[{}]", repo.name);
+        for rank in konpac.rank_mirrors(&repo).await? {
+            let latency = match rank.latency {
+                Some(latency) => format!("{} мс", latency.as_millis()),
+                None => "недоступно".to_string(),
+            };
+            let state = if rank.latency.is_some() && !rank.up_to_date { " (устарело)" } else { "" };
+            println!("    {} {}{}", rank.mirror, latency, state);
+        }
+        info!("Проверка зеркал {} завершена", repo.name);
+    }
+    Ok(())
+}
+
+// Проверка базы пакетов с предложением удалить записи без каталога; true, если проблем не осталось
+fn check_db(db: &LocalDb, yes: bool) -> Result<bool, KonpacError> {
+    let check = db.check()?;
+    println!("Версия схемы: {}", check.schema_version);
+    for problem in &check.integrity {
+        println!("Нарушена целостность: {}", problem);
+    }
+    for package in &check.missing {
+        println!("Нет каталога пакета {} {}: {}", package.name, package.version, package.path);
+    }
+    if check.is_ok() {
+        println!("Проблем не найдено");
+        return Ok(true);
+    }
+    if check.missing.is_empty() {
+        return Ok(false);
+    }
+
+    print!("Удалить из базы {} записей без каталога? [y/N]: ", check.missing.len());
+    std::io::stdout().flush()?;
+    let confirmed = yes || {
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input)?;
+        matches!(input.trim().to_lowercase().as_str(), "y" | "yes")
+    };
+    if yes {
+        println!("y");
+    }
+    if !confirmed {
+        return Ok(false);
+    }
+    if !is_elevated() {
+        return Err(KonpacError::Permission("Для исправления базы требуются права администратора".to_string()));
+    }
+    let removed = db.remove_missing(&check.missing)?;
+    println!("Удалено записей: {}", removed);
+    Ok(check.integrity.is_empty())
+}
+
+// Завершение по ошибке: сообщение с видом ошибки и код завершения, свой для каждого вида
+fn fail(context: &str, e: KonpacError) -> ! {
+    error!("{} [{}]: {}", context, e.kind(), e);
+    std::process::exit(e.exit_code());
+}
+
+// Операции с системой требуют прав администратора
+fn require_root(action: &str) {
+    if !is_elevated() {
+        fail("Ошибка", KonpacError::Permission(format!("Для {} требуются права администратора!", action)));
+    }
+}
+
+// Вывод плана операции для --dry-run: для человека или в JSON
+fn print_dry_run(result: Result<DryRun, KonpacError>, json: bool) {
+    let plan = result.unwrap_or_else(|e| fail("Ошибка планирования", e));
+    if json {
+        match serde_json::to_string_pretty(&plan) {
+            Ok(json) => println!("{}", json),
+            Err(e) => fail("Ошибка вывода плана", KonpacError::Invalid(e.to_string())),
+        }
+        return;
+    }
+    if plan.packages.is_empty() {
+        println!("Нечего делать");
+        return;
+    }
+    println!("Пакеты ({}):", plan.packages.len());
+    for package in &plan.packages {
+        let version = match (&package.old_version, &package.new_version) {
+            (Some(old), Some(new)) => format!("{} -> {}", old, new),
+            (old, new) => old.as_ref().or(new.as_ref()).cloned().unwrap_or_default(),
+        };
+        let source = package.source.as_ref().map(|source| format!(" ({})", source)).unwrap_or_default();
+        let download = package.download.map(|size| format!(", загрузка {} байт", size)).unwrap_or_default();
+        println!("    {} {} {}{}{}", package.action, package.name, version, source, download);
+    }
+    println!("Загрузка: {} байт, запись: {} байт, освобождается: {} байт", plan.download_size, plan.install_size, plan.remove_size);
+    let sections = [
+        ("Создать", &plan.create),
+        ("Перезаписать", &plan.overwrite),
+        ("Удалить", &plan.delete),
+        ("Изменены локально, новая версия как .konpacnew", &plan.konpacnew),
+        ("Изменены локально, сохраняются как .konpacsave", &plan.konpacsave),
+    ];
+    for (title, files) in sections {
+        if !files.is_empty() {
+            println!("{} ({}):", title, files.len());
+            files.iter().for_each(|file| println!("    {}", file.display()));
+        }
+    }
+    if !plan.scripts.is_empty() {
+        println!("Скрипты:");
+        plan.scripts.iter().for_each(|script| println!("    {}", script));
+    }
+    if !plan.unknown.is_empty() {
+        println!("Нет в кэше, файлы, скрипты и зависимости неизвестны до загрузки:");
+        plan.unknown.iter().for_each(|name| println!("    {}", name));
+    }
+}
+
+// Контекст для операций с системой; без базы пакетов konpac не может продолжить.
+// Для --dry-run база открывается только на чтение
+fn open_konpac(read_only: bool, yes: bool, arch: &str, reinstall: bool) -> Konpac {
+    let opened = if read_only { Konpac::open_read_only(Path::new("/")) } else { Konpac::open(Path::new("/")) };
+    let mut konpac = opened.unwrap_or_else(|e| {
+        fail("Ошибка открытия базы пакетов", e);
+    });
+    let options = konpac.options_mut();
+    options.yes = yes;
+    options.arch = arch.to_string();
+    options.reinstall = reinstall;
+    konpac
+}
+
+// Итог изменения индекса; при частичных сбоях завершение с кодом 1
+fn report_index(result: Result<IndexSummary, KonpacError>) {
+    let summary = match result {
+        Ok(summary) => summary,
+        Err(e) => {
+            fail("Ошибка изменения индекса", e);
+        }
+    };
+    println!(
+        "Добавлено: {}, удалено из индекса: {}, удалено архивов: {}, освобождено {} байт",
+        summary.added, summary.removed, summary.deleted, summary.freed
+    );
+    if summary.deltas > 0 {
+        println!("Создано разниц: {}", summary.deltas);
+    }
+    if !summary.failures.is_empty() {
+        for failure in &summary.failures {
+            error!("{}", failure);
+        }
+        error!("Ошибок: {}", summary.failures.len());
+        std::process::exit(1);
+    }
+}
+
+// Вывод репозиториев в порядке, в котором они используются при поиске
+fn print_repos(arch: &str) -> Result<(), KonpacError> {
+    let mut repositories = get_repos(Path::new(REPOS_FILE), Path::new(CREDENTIALS_FILE), arch)?;
+    repositories.sort_by_key(|repo| std::cmp::Reverse(repo.priority));
+    for repo in repositories {
+        let patterns = |patterns: &[glob::Pattern]| match patterns.len() {
+            0 => "-".to_string(),
+            _ => patterns.iter().map(|p| p.as_str()).collect::<Vec<_>>().join(" "),
+        };
+        let status = match (repo.enabled, repo.serves_arch()) {
+            (false, _) => "отключен".to_string(),
+            (true, false) => format!("не обслуживает {}", arch),
+            (true, true) => "включен".to_string(),
+        };
+        println!("[{}] {}", repo.name, status);
+        println!("    url      = {}", repo.url);
+        for mirror in repo.mirrors.iter().skip(1) {
+            println!("    mirror   = {}", mirror);
+        }
+        if let Some(mirrorlist) = &repo.mirrorlist {
+            println!("    mirrorlist = {}", mirrorlist.display());
+        }
+        println!("    priority = {}", repo.priority);
+        println!("    siglevel = {}", repo.siglevel);
+        println!("    arch     = {}", if repo.arches.is_empty() { "-".to_string() } else { repo.arches.join(" ") });
+        println!("    include  = {}", patterns(&repo.include));
+        println!("    exclude  = {}", patterns(&repo.exclude));
+        // Значения заголовков и учетные данные не выводятся
+        println!("    auth     = {}", repo.http.auth.as_ref().map_or("none", |auth| auth.kind()));
+        for (name, _) in &repo.http.headers {
+            println!("    header   = {}: ***", name);
+        }
+        if let Some(ca_cert) = &repo.http.ca_cert {
+            println!("    ca_cert  = {}", ca_cert.display());
+        }
+        if let Some(client_cert) = &repo.http.client_cert {
+            println!("    client_cert = {}", client_cert.display());
+        }
+    }
+    Ok(())
 }
 
 // Основная асинхронная функция
@@ -82,57 +516,236 @@ async fn main() {
     // Приветственное сообщение
     info!("Welcome to konpac :)");
 
+    // Целевая архитектура: хост или явно заданная через --arch
+    let arch = args.arch.unwrap_or_else(|| ARCH.to_string());
+
+    // База пакетов открывается только для операций с системой
+    let konpac = || open_konpac(false, args.yes, &arch, args.reinstall);
+    let preview_konpac = || open_konpac(true, args.yes, &arch, args.reinstall);
+
+    // Подкоманды обрабатываются отдельно от флагов операций
+    if let Some(command) = args.command {
+        match command {
+            Commands::ConfigDiff => {
+                match konpac().pending_configs() {
+                    Ok(pending) if pending.is_empty() => info!("Нет ожидающих .konpacnew файлов"),
+                    Ok(pending) => {
+                        for config in pending {
+                            println!("{}: {} -> {}", config.package, config.path.display(), config.new_path.display());
+                        }
+                    },
+                    Err(e) => {
+                        fail("Ошибка поиска .konpacnew файлов", e);
+                    }
+                }
+            },
+            Commands::Repo { command: RepoCommands::List } => {
+                if let Err(e) = print_repos(&arch) {
+                    fail("Ошибка чтения списка репозиториев", e);
+                }
+            },
+            Commands::Repo { command: RepoCommands::Add { db, packages } } => report_index(add_packages(&db, &packages)),
+            Commands::Repo { command: RepoCommands::Remove { db, name } } => report_index(remove_package(&db, &name)),
+            Commands::Repo { command: RepoCommands::Prune { db, keep, preview } } if preview.dry_run => {
+                print_dry_run(preview_prune(&db, keep as usize), preview.json);
+            },
+            Commands::Repo { command: RepoCommands::Prune { db, keep, .. } } => report_index(prune_repo(&db, keep as usize)),
+            Commands::Repo { command: RepoCommands::Serve { dir, bind, watch, interval, deltas } } => {
+                let watch = watch.then(|| Duration::from_secs(interval.max(1)));
+                if let Err(e) = serve_repo(&dir, &bind, watch, deltas).await {
+                    fail("Ошибка сервера репозитория", e);
+                }
+            },
+            Commands::Install { paths, preview } if preview.dry_run => {
+                print_dry_run(preview_konpac().preview_install_files(&paths).await, preview.json);
+            },
+            Commands::Install { paths, .. } => {
+                require_root("установки пакета");
+                if let Err(e) = konpac().install_files(&paths).await {
+                    fail("Ошибка установки пакетов", e);
+                }
+            },
+            Commands::Downgrade { package } => {
+                require_root("отката пакета");
+                if let Err(e) = konpac().downgrade(&package).await {
+                    fail("Ошибка отката пакета", e);
+                }
+            },
+            Commands::Upgrade { preview } if preview.dry_run => {
+                print_dry_run(preview_konpac().preview_upgrade().await, preview.json);
+            },
+            Commands::Upgrade { .. } => {
+                require_root("обновления");
+                if let Err(e) = konpac().upgrade().await {
+                    fail("Ошибка обновления", e);
+                }
+            },
+            Commands::Autoremove { preview } if preview.dry_run => {
+                print_dry_run(preview_konpac().preview_autoremove().await, preview.json);
+            },
+            Commands::Autoremove { .. } => {
+                require_root("удаления пакетов");
+                match konpac().autoremove().await {
+                    Ok(removed) if removed.is_empty() => println!("Нечего удалять"),
+                    Ok(removed) => println!("Удалено: {}", removed.join(", ")),
+                    Err(e) => fail("Ошибка удаления пакетов", e),
+                }
+            },
+            Commands::Sync => {
+                if let Err(e) = konpac().sync().await {
+                    fail("Ошибка синхронизации", e);
+                }
+            },
+            Commands::Search { term } => {
+                match konpac().search(&term).await {
+                    Ok(found) => {
+                        for (repo, package) in found {
+                            println!("{}/{} {} ({})", repo, package.name, package.version, package.arch);
+                        }
+                    },
+                    Err(e) => {
+                        fail("Ошибка поиска", e);
+                    }
+                }
+            },
+            Commands::Query { package } => {
+                match konpac().query(package.as_deref()).await {
+                    Ok(installed) if installed.is_empty() && package.is_some() => {
+                        fail("Ошибка запроса", KonpacError::NotFound(format!("Пакет '{}' не установлен", package.unwrap_or_default())));
+                    },
+                    Ok(installed) => installed.iter().for_each(|package| println!("{} {}", package.name, package.version)),
+                    Err(e) => {
+                        fail("Ошибка чтения базы пакетов", e);
+                    }
+                }
+            },
+            Commands::Hold { packages } if packages.is_empty() => {
+                match konpac().db().held() {
+                    Ok(held) => held.iter().for_each(|name| println!("{}", name)),
+                    Err(e) => {
+                        fail("Ошибка чтения закрепленных пакетов", e);
+                    }
+                }
+            },
+            Commands::Hold { packages } => {
+                let konpac = konpac();
+                for name in packages {
+                    match konpac.db().hold(&name) {
+                        Ok(()) => info!("Пакет {} закреплен", name),
+                        Err(e) => {
+                            fail(&format!("Ошибка закрепления пакета {}", name), e);
+                        }
+                    }
+                }
+            },
+            Commands::Unhold { packages } => {
+                let konpac = konpac();
+                for name in packages {
+                    match konpac.db().unhold(&name) {
+                        Ok(true) => info!("Закрепление пакета {} снято", name),
+                        Ok(false) => info!("Пакет {} не был закреплен", name),
+                        Err(e) => {
+                            fail(&format!("Ошибка снятия закрепления пакета {}", name), e);
+                        }
+                    }
+                }
+            },
+            Commands::History { package } => {
+                match konpac().db().history(package.as_deref()) {
+                    Ok(history) => {
+                        for entry in history {
+                            println!("{} {} {} {} -> {}", entry.time, entry.action, entry.name,
+                                entry.old_version.as_deref().unwrap_or("-"), entry.new_version.as_deref().unwrap_or("-"));
+                        }
+                    },
+                    Err(e) => {
+                        fail("Ошибка чтения истории", e);
+                    }
+                }
+            },
+            Commands::Cache { command: CacheCommands::Clean { keep, uninstalled } } => {
+                let policy = if uninstalled { CleanPolicy::Uninstalled } else { CleanPolicy::Keep(keep) };
+                let konpac = konpac();
+                match clean_cache(konpac.db(), konpac.root(), policy) {
+                    Ok(summary) => println!("Удалено архивов: {}, освобождено {} байт", summary.removed, summary.freed),
+                    Err(e) => {
+                        fail("Ошибка очистки кэша", e);
+                    }
+                }
+            },
+            Commands::Db { command: DbCommands::Check } => {
+                match check_db(konpac().db(), args.yes) {
+                    Ok(true) => {}
+                    Ok(false) => std::process::exit(1),
+                    Err(e) => {
+                        fail("Ошибка проверки базы пакетов", e);
+                    }
+                }
+            },
+            Commands::Mirror { command: MirrorCommands::Rank { repo } } => {
+                if let Err(e) = rank_repo_mirrors(&konpac(), repo).await {
+                    fail("Ошибка проверки зеркал", e);
+                }
+            },
+        }
+        return;
+    }
+
+    // План операции вместо ее выполнения
+    if args.dry_run {
+        let konpac = preview_konpac();
+        let plan = match (args.install, args.remove, args.download) {
+            (Some(install_path), _, _) => konpac.preview_install_files(&[PathBuf::from(install_path)]).await,
+            (_, Some(package_name), _) => konpac.preview_remove(&package_name).await,
+            (_, _, Some(package_name)) => konpac.preview_install(&package_name).await,
+            _ => unreachable!(),
+        };
+        print_dry_run(plan, args.json);
+        return;
+    }
+
     // Обрабатываем аргументы в зависимости от выбранной операции
     match (args.install, args.remove, args.gen_repo, args.get_repo, args.download) {
         // Установка пакета из файла
         (Some(install_path), None, None, None, None) => {
             // Проверяем, есть ли права администратора
-            if !is_elevated() {
-                error!("Ошибка: Для установки пакета требуются права администратора!");
-                std::process::exit(1);
-            }
+            require_root("установки пакета");
             // Устанавливаем пакет из указанного файла
             let install_package_path = Path::new(&install_path);
-            match install_package_from_file(install_package_path, args.yes).await {
+            match konpac().install_file(install_package_path).await {
                 Ok(_) => info!("Installed Success"),
-                Err(e) => error!("Error in installation: {}", e)
+                Err(e) => fail("Error in installation", e)
             };
         },
         // Удаление пакета по имени
         (None, Some(package_name), None, None, None) => {
             // Проверяем, есть ли права администратора
-            if !is_elevated() {
-                error!("Ошибка: Для удаления пакета требуются права администратора!");
-                std::process::exit(1);
-            }
+            require_root("удаления пакета");
             // Удаляем пакет и обрабатываем возможные ошибки
-            uninstall_package(package_name).unwrap_or_else(|e| {
-                error!("Ошибка удаления пакета: {}", e);
-                std::process::exit(1);
+            konpac().remove(&package_name).await.unwrap_or_else(|e| {
+                fail("Ошибка удаления пакета", e);
             });
         },
         // Генерация репозитория из папки с пакетами
         (None, None, Some(repo_path), None, None) => {
             // Генерируем репозиторий из указанной папки
-            let repo_path = Path::new(&repo_path).to_path_buf();
-            generate_repo(repo_path);
+            report_index(generate_repo(Path::new(&repo_path), args.deltas));
         },
         // Получение репозиториев
         (None, None, None, Some(get_repo), None) => {
             // Получаем репозитории из указанного пути
-            get_repos(Path::new(&get_repo));
+            if let Err(e) = get_repos(Path::new(&get_repo), Path::new(CREDENTIALS_FILE), &arch) {
+                fail("Ошибка чтения списка репозиториев", e);
+            }
         },
         // Установка пакета из репозитория
         (None, None, None, None, Some(package_name)) => {
             // Проверяем, есть ли права администратора
-            if !is_elevated() {
-                error!("Ошибка: Для установки пакета требуются права администратора!");
-                std::process::exit(1);
-            }
+            require_root("установки пакета");
             // Устанавливаем пакет из репозитория и обрабатываем результат
-            match install_from_repo(&package_name, args.yes).await {
+            match konpac().install(&package_name).await {
                 Ok(_) => { info!("Installing success") },
-                Err(e) => { error!("Error in package install: {}", e) }
+                Err(e) => fail("Error in package install", e)
             };
         },
         // Обработка недопустимых комбинаций аргументов
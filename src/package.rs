@@ -1,6 +1,15 @@
+pub mod archive;
+pub mod autoremove;
+pub mod backup;
+pub mod cache;
+pub mod filelist;
 pub mod install;
+pub mod preview;
 pub mod remove;
+pub mod stage;
+pub mod transaction;
+pub mod upgrade;
 pub mod utils;
-mod depencies;
-pub mod update;
+pub mod depencies;
+pub mod downgrade;
 pub mod build;
\ No newline at end of file
@@ -0,0 +1,160 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use tar::Archive;
+
+// Расширения файлов, которые считаются пакетами
+pub const PACKAGE_EXTENSIONS: [&str; 6] = ["kpkg", "tar.gz", "tgz", "tar.zst", "tar.xz", "tar"];
+
+// Сигнатуры сжатых потоков
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+
+// Метод сжатия tar-архива пакета
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    // Определение метода сжатия по первым байтам файла
+    pub fn from_magic(header: &[u8]) -> Self {
+        if header.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if header.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else {
+            Compression::None
+        }
+    }
+
+    // Обертка над потоком, распаковывающая его
+    pub fn decoder<'a, R: Read + 'a>(self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(GzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+            Compression::Xz => Box::new(xz2::read::XzDecoder::new(reader)),
+        })
+    }
+
+    // Обертка над потоком, сжимающая его; finish() вызывается через Encoder
+    pub fn encoder<'a, W: Write + 'a>(self, writer: W) -> io::Result<Encoder<'a, W>> {
+        Ok(match self {
+            Compression::None => Encoder::None(writer),
+            Compression::Gzip => Encoder::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(writer, 0)?),
+            Compression::Xz => Encoder::Xz(xz2::write::XzEncoder::new(writer, 6)),
+        })
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" | "tar" => Ok(Compression::None),
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            "xz" => Ok(Compression::Xz),
+            _ => Err(format!("Неизвестный метод сжатия '{}'. Используйте gzip, zstd, xz или none", s)),
+        }
+    }
+}
+
+// Сжимающий поток, который нужно явно завершить
+pub enum Encoder<'a, W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'a, W>),
+    Xz(xz2::write::XzEncoder<W>),
+}
+
+impl<W: Write> Encoder<'_, W> {
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(w) => Ok(w),
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Zstd(e) => e.finish(),
+            Encoder::Xz(e) => e.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(w) => w.write(buf),
+            Encoder::Gzip(e) => e.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+            Encoder::Xz(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(w) => w.flush(),
+            Encoder::Gzip(e) => e.flush(),
+            Encoder::Zstd(e) => e.flush(),
+            Encoder::Xz(e) => e.flush(),
+        }
+    }
+}
+
+// Распаковка потока с автоматическим определением сжатия
+pub fn decompress<'a, R: BufRead + 'a>(mut reader: R) -> io::Result<Box<dyn Read + 'a>> {
+    let compression = Compression::from_magic(reader.fill_buf()?);
+    compression.decoder(reader)
+}
+
+// Открытие архива пакета любого поддерживаемого формата
+pub fn open_archive(path: &Path) -> io::Result<Archive<Box<dyn Read>>> {
+    let file = BufReader::new(File::open(path)?);
+    Ok(Archive::new(decompress(file)?))
+}
+
+// Несжатый архив из файлов (путь, содержимое) в заданном порядке, для тестов
+#[cfg(test)]
+pub fn write_archive(path: &Path, entries: &[(&str, &str)]) {
+    let mut builder = tar::Builder::new(File::create(path).unwrap());
+    for (name, content) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_cksum();
+        builder.append_data(&mut header, name, content.as_bytes()).unwrap();
+    }
+    builder.finish().unwrap();
+}
+
+// Проверка, что файл похож на пакет по расширению
+pub fn is_package_file(path: &Path) -> bool {
+    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    PACKAGE_EXTENSIONS.iter().any(|ext| file_name.ends_with(&format!(".{}", ext)))
+}
@@ -0,0 +1,67 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use log::info;
+use crate::db::LocalDb;
+use crate::error::KonpacError;
+use crate::package::depencies::PackageQuery;
+use crate::package::install::{parse_manifest, InstallOptions};
+use crate::package::remove::uninstall_package;
+use crate::package::transaction::install_order;
+use crate::repo::utils::split_repo_prefix;
+
+// Имена зависимостей установленного пакета из его сохраненного манифеста
+fn installed_depens(package_dir: &Path) -> Result<Vec<String>, KonpacError> {
+    if !package_dir.join("package.yml").exists() {
+        return Ok(Vec::new());
+    }
+    Ok(parse_manifest(package_dir)?.depens.iter()
+        .filter_map(|depen| PackageQuery::parse(depen).ok())
+        .map(|depency| split_repo_prefix(&depency.name).1.to_string())
+        .collect())
+}
+
+// Пакеты, установленные только как зависимости и больше никому не нужные, в порядке удаления:
+// зависящие раньше своих зависимостей. Закрепленные пакеты и все, что им нужно, остаются
+pub fn orphans(db: &LocalDb, options: &InstallOptions) -> Result<Vec<String>, KonpacError> {
+    let mut depens = HashMap::new();
+    for package in db.packages()? {
+        depens.insert(package.name, installed_depens(Path::new(&package.path))?);
+    }
+    let marked = db.depencies()?;
+
+    // Удаление пакета может сделать ненужными его зависимости, поэтому поиск повторяется
+    let mut orphans: HashSet<String> = HashSet::new();
+    loop {
+        let needed: HashSet<&String> = depens.iter()
+            .filter(|(name, _)| !orphans.contains(*name))
+            .flat_map(|(_, names)| names)
+            .collect();
+        let mut found = Vec::new();
+        for name in marked.iter().filter(|name| !orphans.contains(*name) && !needed.contains(name)) {
+            if options.frozen_reason(db, name)?.is_none() {
+                found.push(name.clone());
+            }
+        }
+        if found.is_empty() {
+            break;
+        }
+        orphans.extend(found);
+    }
+
+    let mut orphans: Vec<String> = orphans.into_iter().collect();
+    orphans.sort();
+    let mut order = install_order(orphans, |name| name, &depens);
+    order.reverse();
+    Ok(order)
+}
+
+// Удаление ненужных зависимостей; возвращает имена удаленных пакетов
+pub fn autoremove(db: &LocalDb, options: &InstallOptions) -> Result<Vec<String>, KonpacError> {
+    info!("Поиск ненужных зависимостей: Поиск");
+    let orphans = orphans(db, options)?;
+    info!("Поиск ненужных зависимостей завершен: {} пакетов", orphans.len());
+    for name in &orphans {
+        uninstall_package(db, name.clone(), options)?;
+    }
+    Ok(orphans)
+}
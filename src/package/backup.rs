@@ -0,0 +1,63 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use crate::error::KonpacError;
+use crate::consts::paths::rooted;
+use crate::db::LocalDb;
+use crate::package::install::parse_manifest;
+
+// sha256 файла на диске
+pub fn file_sha256(path: &Path) -> io::Result<String> {
+    reader_sha256(&mut File::open(path)?)
+}
+
+// sha256 потока, например записи архива
+pub fn reader_sha256(reader: &mut impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// Куда кладется новая версия измененного конфигурационного файла
+pub fn konpacnew_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".konpacnew")
+}
+
+// Куда сохраняется измененный конфигурационный файл при удалении пакета
+pub fn konpacsave_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".konpacsave")
+}
+
+// Новая версия конфигурационного файла, ожидающая слияния
+pub struct PendingConfig {
+    pub package: String,
+    pub path: PathBuf,
+    pub new_path: PathBuf,
+}
+
+// Ожидающие .konpacnew файлы установленных пакетов
+pub fn pending_konpacnew(db: &LocalDb, root: &Path) -> Result<Vec<PendingConfig>, KonpacError> {
+    let mut pending = Vec::new();
+    for package in db.packages()? {
+        let package_dir = Path::new(&package.path);
+        if !package_dir.join("package.yml").exists() {
+            continue;
+        }
+        let manifest = parse_manifest(package_dir)?;
+        for path in manifest.backup {
+            let path = rooted(root, &path);
+            let new_path = konpacnew_path(&path);
+            if new_path.exists() {
+                pending.push(PendingConfig { package: package.name.clone(), path, new_path });
+            }
+        }
+    }
+    Ok(pending)
+}
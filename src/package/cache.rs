@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use crate::error::KonpacError;
+use std::fs;
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use crate::consts::paths::{rooted, CACHE_PATH};
+use crate::db::LocalDb;
+use crate::package::archive::is_package_file;
+use crate::package::backup::file_sha256;
+use crate::package::install::read_manifest_from_archive;
+use crate::package::utils::compare_versions;
+use crate::repo::delta::DELTA_EXTENSION;
+use crate::db::RepoPackage;
+
+// Путь архива в кэше системы с корнем root: имя, версия и sha256 из индекса
+pub fn cache_path(root: &Path, package: &RepoPackage) -> PathBuf {
+    let file_name = match &package.sha256 {
+        Some(sha256) => format!("{}-{}-{}.kpkg", package.name, package.version, sha256),
+        // Без sha256 в индексе архив нельзя сверить, он загружается каждый раз
+        None => format!("{}-{}.kpkg", package.name, package.version),
+    };
+    rooted(root, CACHE_PATH).join(file_name)
+}
+
+// Архив уже лежит в кэше и совпадает с индексом
+pub fn is_cached(root: &Path, package: &RepoPackage) -> bool {
+    let Some(expected) = &package.sha256 else {
+        return false;
+    };
+    let path = cache_path(root, package);
+    match file_sha256(&path) {
+        Ok(sha256) if &sha256 == expected => true,
+        Ok(_) => {
+            warn!("Архив в кэше поврежден и будет загружен заново: {:?}", path);
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+// Что удаляет konpac cache clean
+pub enum CleanPolicy {
+    // Оставить N новейших версий каждого пакета
+    Keep(usize),
+    // Оставить только установленные версии
+    Uninstalled,
+}
+
+// Итог очистки кэша
+#[derive(Default)]
+pub struct CleanSummary {
+    pub removed: usize,
+    pub freed: u64,
+}
+
+impl CleanSummary {
+    fn remove(&mut self, path: &Path) -> Result<(), KonpacError> {
+        let size = fs::metadata(path)?.len();
+        fs::remove_file(path)?;
+        info!("Удален {:?}", path);
+        self.removed += 1;
+        self.freed += size;
+        Ok(())
+    }
+}
+
+// Версии пакетов в кэше: имя пакета -> (версия, путь архива)
+type CachedVersions = HashMap<String, Vec<(String, PathBuf)>>;
+
+// Версии в кэше по пакетам; архивы, из которых не читается манифест, возвращаются отдельно
+fn scan_cache(root: &Path) -> Result<(CachedVersions, Vec<PathBuf>), KonpacError> {
+    let mut cached = CachedVersions::new();
+    let mut broken = Vec::new();
+    let cache_dir = rooted(root, CACHE_PATH);
+    if !cache_dir.exists() {
+        return Ok((cached, broken));
+    }
+    for entry in fs::read_dir(&cache_dir)? {
+        let path = entry?.path();
+        if !is_package_file(&path) {
+            continue;
+        }
+        match read_manifest_from_archive(&path) {
+            Ok(manifest) => cached.entry(manifest.name).or_default().push((manifest.version, path)),
+            Err(e) => {
+                warn!("Поврежденный архив в кэше {:?}: {}", path, e);
+                broken.push(path);
+            }
+        }
+    }
+    // От новых версий к старым
+    for versions in cached.values_mut() {
+        versions.sort_by(|a, b| compare_versions(&b.0, &a.0));
+    }
+    Ok((cached, broken))
+}
+
+// Версии пакета в кэше, от новой к старой
+pub fn cached_versions(root: &Path, name: &str) -> Result<Vec<(String, PathBuf)>, KonpacError> {
+    let (mut cached, _) = scan_cache(root)?;
+    Ok(cached.remove(name).unwrap_or_default())
+}
+
+// Очистка кэша; недокачанные .part файлы, разницы и поврежденные архивы удаляются всегда
+pub fn clean_cache(db: &LocalDb, root: &Path, policy: CleanPolicy) -> Result<CleanSummary, KonpacError> {
+    let mut summary = CleanSummary::default();
+    let cache_dir = rooted(root, CACHE_PATH);
+    if !cache_dir.exists() {
+        return Ok(summary);
+    }
+    for entry in fs::read_dir(&cache_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "part" || extension == DELTA_EXTENSION) {
+            summary.remove(&path)?;
+        }
+    }
+    let (cached, broken) = scan_cache(root)?;
+    for path in broken {
+        summary.remove(&path)?;
+    }
+
+    let installed: HashMap<String, String> = db.packages()?
+        .into_iter()
+        .map(|package| (package.name, package.version))
+        .collect();
+
+    for (name, versions) in cached {
+        for (index, (version, path)) in versions.iter().enumerate() {
+            let keep = match policy {
+                CleanPolicy::Keep(keep) => index < keep,
+                CleanPolicy::Uninstalled => installed.get(&name) == Some(version),
+            };
+            if !keep {
+                summary.remove(path)?;
+            }
+        }
+    }
+    Ok(summary)
+}
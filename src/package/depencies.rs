@@ -1,3 +1,7 @@
+use crate::error::KonpacError;
+use std::cmp::Ordering;
+use crate::package::utils::compare_versions;
+
 #[derive(Debug, PartialEq)]
 pub struct PackageQuery {
     pub name: String,
@@ -6,21 +10,25 @@ pub struct PackageQuery {
 }
 
 impl PackageQuery {
-    // Метод для парсинга строки типа "example>=1.1.1alpha"
-    pub fn parse(query: &str) -> Result<Self, &'static str> {
+    // Метод для парсинга строки типа "example>=1.1.1alpha".
+    // Строка без оператора означает любую версию пакета
+    pub fn parse(query: &str) -> Result<Self, KonpacError> {
         // Сначала ищем двухсимвольные операторы
         let operators = ["<=", ">=", "=", "<", ">"];
 
         // Ищем оператор в строке
-        let operator = operators
-            .iter()
-            .find(|&&op| query.contains(op))
-            .ok_or("Не удалось найти оператор сравнения")?;
+        let Some(operator) = operators.iter().find(|&&op| query.contains(op)) else {
+            let name = query.trim().to_string();
+            if name.is_empty() {
+                return Err(KonpacError::Invalid("Имя пакета не может быть пустым".to_string()));
+            }
+            return Ok(PackageQuery { name, version: String::new(), comparison_operator: String::new() });
+        };
 
         // Разделяем строку на имя пакета и версию
         let parts: Vec<&str> = query.split(operator).collect();
         if parts.len() != 2 {
-            return Err("Некорректный формат строки");
+            return Err(KonpacError::Invalid(format!("Некорректный формат строки: {}", query)));
         }
 
         let name = parts[0].trim().to_string();
@@ -28,7 +36,7 @@ impl PackageQuery {
 
         // Проверяем, что имя и версия не пустые
         if name.is_empty() || version.is_empty() {
-            return Err("Имя пакета или версия не могут быть пустыми");
+            return Err(KonpacError::Invalid("Имя пакета или версия не могут быть пустыми".to_string()));
         }
 
         Ok(PackageQuery {
@@ -37,4 +45,68 @@ impl PackageQuery {
             comparison_operator: operator.to_string(),
         })
     }
-}
\ No newline at end of file
+
+    // Подходит ли версия под ограничение
+    pub fn matches(&self, version: &str) -> bool {
+        version_matches(&self.comparison_operator, version, &self.version)
+    }
+}
+
+// Сравнение версии с ограничением; пустой оператор подходит под любую версию
+pub fn version_matches(operator: &str, version: &str, constraint: &str) -> bool {
+    let ordering = compare_versions(version, constraint);
+    match operator {
+        "" => true,
+        "=" => ordering == Ordering::Equal,
+        "<" => ordering == Ordering::Less,
+        ">" => ordering == Ordering::Greater,
+        "<=" => ordering != Ordering::Greater,
+        ">=" => ordering != Ordering::Less,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, operator: &str, version: &str) -> PackageQuery {
+        PackageQuery { name: name.to_string(), version: version.to_string(), comparison_operator: operator.to_string() }
+    }
+
+    #[test]
+    fn parse_queries() {
+        assert_eq!(PackageQuery::parse("lmms").unwrap(), query("lmms", "", ""));
+        assert_eq!(PackageQuery::parse(" extra/lmms ").unwrap(), query("extra/lmms", "", ""));
+        assert_eq!(PackageQuery::parse("lmms=1.2.2").unwrap(), query("lmms", "=", "1.2.2"));
+        assert_eq!(PackageQuery::parse("lmms<2.0").unwrap(), query("lmms", "<", "2.0"));
+        assert_eq!(PackageQuery::parse("lmms>1.0").unwrap(), query("lmms", ">", "1.0"));
+        assert_eq!(PackageQuery::parse("lmms<=2.0").unwrap(), query("lmms", "<=", "2.0"));
+        assert_eq!(PackageQuery::parse("extra/lmms >= 1.1.1alpha").unwrap(), query("extra/lmms", ">=", "1.1.1alpha"));
+
+        for invalid in ["", "  ", "=1.0", "lmms=", "lmms>=", "lmms=1=2", "<=1.0"] {
+            assert!(matches!(PackageQuery::parse(invalid), Err(KonpacError::Invalid(_))), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn queries_match_versions() {
+        let bare = PackageQuery::parse("lmms").unwrap();
+        assert!(bare.matches("0.1") && bare.matches("99"));
+
+        let cases = [
+            ("lmms=1.2", [false, true, false]),
+            ("lmms<1.2", [true, false, false]),
+            ("lmms>1.2", [false, false, true]),
+            ("lmms<=1.2", [true, true, false]),
+            ("lmms>=1.2", [false, true, true]),
+        ];
+        for (query, expected) in cases {
+            let query = PackageQuery::parse(query).unwrap();
+            // Версии сравниваются по числам, а не как строки: 1.10 новее 1.9
+            let matched = ["1.1", "1.2", "1.10"].map(|version| query.matches(version));
+            assert_eq!(matched, expected, "{:?}", query);
+        }
+        assert!(!version_matches("~", "1.0", "1.0"));
+    }
+}
@@ -0,0 +1,56 @@
+use std::cmp::Ordering;
+use crate::error::KonpacError;
+use log::{info, error};
+use crate::db::LocalDb;
+use crate::package::cache::cached_versions;
+use crate::package::install::{install_from_repo, install_package_from_file, InstallOptions};
+use crate::package::utils::compare_versions;
+use crate::repo::utils::{candidate_repos, package_versions, split_repo_prefix};
+
+// Версия a старее версии b
+fn older(a: &str, b: &str) -> bool {
+    compare_versions(a, b) == Ordering::Less
+}
+
+// Откат пакета на ближайшую более старую версию из кэша или репозиториев.
+// Запрос "repo/package" ограничивает поиск одним репозиторием
+pub async fn downgrade_package(db: &LocalDb, query: &str, options: &InstallOptions) -> Result<(), KonpacError> {
+    let (repo_name, name) = split_repo_prefix(query);
+    let installed = db.installed(name)?
+        .ok_or_else(|| KonpacError::NotFound(format!("Пакет '{}' не установлен", name)))?;
+
+    info!("Поиск предыдущей версии: Поиск");
+    // Версии отсортированы от новой к старой, первая старее установленной и есть предыдущая
+    let cached = cached_versions(&options.root, name)?
+        .into_iter()
+        .find(|(version, _)| older(version, &installed.version));
+
+    let mut from_repo: Option<String> = None;
+    for repo in candidate_repos(options.repos()?, repo_name, name) {
+        match package_versions(&options.fetcher, name, &repo).await {
+            Ok(versions) => {
+                let previous = versions.into_iter().find(|package| older(&package.version, &installed.version));
+                if let Some(previous) = previous {
+                    if from_repo.as_ref().is_none_or(|version| older(version, &previous.version)) {
+                        from_repo = Some(previous.version);
+                    }
+                }
+            }
+            Err(e) => error!("Ошибка поиска в репозитории {}: {}", repo.name, e),
+        }
+    }
+    info!("Поиск предыдущей версии завершен");
+
+    match (cached, from_repo) {
+        // Кэш выигрывает, если в нем версия не старее найденной в репозиториях
+        (Some((version, path)), from_repo) if from_repo.as_ref().is_none_or(|repo_version| !older(&version, repo_version)) => {
+            info!("Откат {} {} -> {} из кэша", name, installed.version, version);
+            install_package_from_file(db, &path, None, options).await
+        }
+        (_, Some(version)) => {
+            info!("Откат {} {} -> {} из репозитория", name, installed.version, version);
+            install_from_repo(db, &format!("{}={}", query, version), options).await
+        }
+        _ => Err(KonpacError::NotFound(format!("Нет версии {} старее {}", name, installed.version))),
+    }
+}
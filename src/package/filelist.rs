@@ -0,0 +1,154 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+// Тип записи в списке файлов пакета
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Hardlink,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+}
+
+impl FileKind {
+    fn as_char(self) -> char {
+        match self {
+            FileKind::File => 'f',
+            FileKind::Dir => 'd',
+            FileKind::Symlink => 'l',
+            FileKind::Hardlink => 'h',
+            FileKind::Fifo => 'p',
+            FileKind::CharDevice => 'c',
+            FileKind::BlockDevice => 'b',
+        }
+    }
+
+    fn from_char(c: &str) -> Option<Self> {
+        match c {
+            "f" => Some(FileKind::File),
+            "d" => Some(FileKind::Dir),
+            "l" => Some(FileKind::Symlink),
+            "h" => Some(FileKind::Hardlink),
+            "p" => Some(FileKind::Fifo),
+            "c" => Some(FileKind::CharDevice),
+            "b" => Some(FileKind::BlockDevice),
+            _ => None,
+        }
+    }
+}
+
+// Сведения об одном установленном объекте пакета
+#[derive(Debug, Clone)]
+pub struct FileRecord {
+    pub path: PathBuf,
+    pub kind: FileKind,
+    pub mode: u32,
+    pub size: u64,
+    pub sha256: String,
+    pub uid: u32,
+    pub gid: u32,
+    // Цель ссылки или "major:minor" для устройств
+    pub target: String,
+}
+
+impl FileRecord {
+    // Строка package.list: путь, тип, права, размер, sha256, владелец, группа и цель через табуляцию
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{:o}\t{}\t{}\t{}\t{}\t{}",
+            self.path.display(),
+            self.kind.as_char(),
+            self.mode,
+            self.size,
+            self.sha256,
+            self.uid,
+            self.gid,
+            self.target
+        )
+    }
+
+    // Старые списки содержат только путь, остальные поля тогда пустые
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+        let path = fields.next()?.trim();
+        if path.is_empty() {
+            return None;
+        }
+        let kind = fields.next().and_then(FileKind::from_char).unwrap_or(FileKind::File);
+        let mode = fields.next().and_then(|m| u32::from_str_radix(m, 8).ok()).unwrap_or(0);
+        let size = fields.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+        let sha256 = fields.next().unwrap_or("").to_string();
+        let uid = fields.next().and_then(|u| u.parse().ok()).unwrap_or(0);
+        let gid = fields.next().and_then(|g| g.parse().ok()).unwrap_or(0);
+        let target = fields.next().unwrap_or("").to_string();
+        Some(FileRecord { path: PathBuf::from(path), kind, mode, size, sha256, uid, gid, target })
+    }
+}
+
+pub fn write_package_list(list_path: &Path, records: &[FileRecord]) -> io::Result<()> {
+    let mut file = File::create(list_path)?;
+    for record in records {
+        writeln!(file, "{}", record.to_line())?;
+    }
+    Ok(())
+}
+
+pub fn read_package_list(list_path: &Path) -> io::Result<Vec<FileRecord>> {
+    let reader = BufReader::new(File::open(list_path)?);
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        if let Some(record) = FileRecord::from_line(&line?) {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::package::stage::WorkDir;
+
+    fn record(path: &str, kind: FileKind, mode: u32, target: &str) -> FileRecord {
+        FileRecord { path: PathBuf::from(path), kind, mode, size: 3, sha256: "ab".repeat(32), uid: 1000, gid: 100, target: target.to_string() }
+    }
+
+    #[test]
+    fn package_list_round_trip() {
+        let work_dir = WorkDir::temp("filelist");
+        let list = work_dir.path().join("package.list");
+        let records = [
+            record("/usr/bin/tool", FileKind::File, 0o4755, ""),
+            record("/usr/share/tool dir", FileKind::Dir, 0o755, ""),
+            record("/usr/bin/alias", FileKind::Symlink, 0o777, "tool"),
+            record("/usr/bin/copy", FileKind::Hardlink, 0o755, "/usr/bin/tool"),
+            record("/run/tool.fifo", FileKind::Fifo, 0o600, ""),
+            record("/dev/tool", FileKind::CharDevice, 0o660, "10:200"),
+            record("/dev/toolblk", FileKind::BlockDevice, 0o660, "8:1"),
+        ];
+        write_package_list(&list, &records).unwrap();
+        let read = read_package_list(&list).unwrap();
+        assert_eq!(read.len(), records.len());
+        for (read, written) in read.iter().zip(&records) {
+            assert_eq!(read.to_line(), written.to_line());
+        }
+    }
+
+    #[test]
+    fn legacy_list_has_only_paths() {
+        let work_dir = WorkDir::temp("filelist-legacy");
+        let list = work_dir.path().join("package.list");
+        fs::write(&list, "/usr/bin/old\n\n/etc/old.conf\n").unwrap();
+        let read = read_package_list(&list).unwrap();
+        let paths: Vec<&Path> = read.iter().map(|record| record.path.as_path()).collect();
+        assert_eq!(paths, [Path::new("/usr/bin/old"), Path::new("/etc/old.conf")]);
+        let legacy = &read[0];
+        assert_eq!((legacy.kind, legacy.mode, legacy.size, legacy.uid, legacy.gid), (FileKind::File, 0, 0, 0, 0));
+        assert!(legacy.sha256.is_empty() && legacy.target.is_empty());
+    }
+}
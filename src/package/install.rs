@@ -1,271 +1,561 @@
-extern crate flate2;
 extern crate yaml_rust2;
 extern crate tar;
 extern crate fs_extra;
-extern crate sha2;
 extern crate indicatif;
 extern crate pretty_env_logger;
 
-use sha2::{Sha256, Digest};
-use flate2::read::GzDecoder;
 use yaml_rust2::YamlLoader;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::fs;
 use std::fs::File;
-use tar::Archive;
-use std::io::{self, Read, Write};
-use std::error::Error;
+use std::io::{self, BufReader, Read, Write};
+use crate::error::KonpacError;
 use fs_extra::dir::{copy, CopyOptions};
-use crate::package::utils::{add_package, check_exist_pkg, PackageManifest, script_executor};
-use crate::repo::utils::{get_repos, search_pkg, fetch_url, find_package_by_version};
+use crate::db::LocalDb;
+use crate::package::utils::{PackageManifest, script_executor};
+use crate::package::remove::{package_backup, remove_files};
+use crate::repo::utils::{get_repos, split_repo_prefix, Repository, ARCH_ANY};
+use crate::package::transaction::{execute, install_order, plan_depencies, plan_install};
 use crate::package::depencies::PackageQuery;
-use crate::consts::paths::{TMP_PATH, DB_PATH, REPOS_FILE};
-use walkdir::WalkDir;
-use log::{info, error};
-use indicatif::{ProgressBar, ProgressStyle};
-use pretty_env_logger::formatted_builder;
+use crate::package::archive::{decompress, open_archive};
+use crate::package::filelist::{read_package_list, write_package_list, FileKind, FileRecord};
+use crate::package::stage::{normalize_entry_path, EntryMeta, HashingReader, StagedInstall, WorkDir};
+use crate::consts::paths::{rooted, TMP_PATH, PACKAGES_PATH};
+use crate::context::{Event, ProgressCallback};
+use crate::repo::mirror::Fetcher;
+use glob::Pattern;
+use tar::{Archive, Entries};
+use log::info;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use std::pin::Pin;
-use futures::Future;
-
-fn setup_logger() {
-    static INIT: std::sync::Once = std::sync::Once::new();
-    INIT.call_once(|| {
-        formatted_builder()
-            .filter(None, log::LevelFilter::Info)
-            .init();
-    });
-}
 
-pub fn parse_manifest(path: &Path) -> Result<PackageManifest, Box<dyn Error>> {
+pub fn parse_manifest(path: &Path) -> Result<PackageManifest, KonpacError> {
     let manifest_path = path.join("package.yml");
     let content = fs::read_to_string(&manifest_path)
-        .map_err(|e| format!("Failed to read manifest: {}", e))?;
-    let docs = YamlLoader::load_from_str(&content)
-        .map_err(|e| format!("Invalid YAML syntax: {}", e))?;
-    let root = docs.first().ok_or("Empty YAML document")?;
-    let name = root["name"].as_str().ok_or("Missing required field 'name'")?.to_string();
-    let version = root["version"].as_str().ok_or("Missing required field 'version'")?.to_string();
+        .map_err(|e| KonpacError::from(e).context("Failed to read manifest"))?;
+    parse_manifest_str(&content)
+}
+
+pub fn parse_manifest_str(content: &str) -> Result<PackageManifest, KonpacError> {
+    let docs = YamlLoader::load_from_str(content)
+        .map_err(|e| KonpacError::Invalid(format!("Invalid YAML syntax: {}", e)))?;
+    let invalid = |message: &str| KonpacError::Invalid(message.to_string());
+    let root = docs.first().ok_or_else(|| invalid("Empty YAML document"))?;
+    let name = root["name"].as_str().ok_or_else(|| invalid("Missing required field 'name'"))?.to_string();
+    let version = root["version"].as_str().ok_or_else(|| invalid("Missing required field 'version'"))?.to_string();
+    let arch = root["arch"].as_str().unwrap_or(ARCH_ANY).to_string();
     let depens = root["depens"].as_vec()
         .map(|arr| arr.iter().filter_map(|v| v.as_str().map(|s| s.to_string())).collect())
-        .unwrap_or_else(Vec::new);
-    Ok(PackageManifest { name, version, depens })
+        .unwrap_or_default();
+    let backup = root["backup"].as_vec()
+        .map(|arr| arr.iter()
+            .filter_map(|v| v.as_str())
+            .map(|path| Path::new("/").join(path.trim_start_matches('/')))
+            .collect())
+        .unwrap_or_default();
+    Ok(PackageManifest { name, version, arch, depens, backup })
+}
+
+// Содержимое package.yml из начала архива: сборка кладет его первым, поэтому манифест
+// читается без распаковки остального, а установка читает архив за один проход.
+// Записи каталогов перед ним пропускаются
+fn read_manifest_entry<R: Read>(entries: &mut Entries<R>, path: &Path) -> Result<String, KonpacError> {
+    for entry in entries {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        if normalize_entry_path(&entry.path()?).as_deref() != Some(Path::new("package.yml")) {
+            break;
+        }
+        let mut content = String::new();
+        entry.read_to_string(&mut content)?;
+        return Ok(content);
+    }
+    Err(KonpacError::Invalid(format!("package.yml должен быть первым файлом архива {}", path.display())))
+}
+
+// Чтение манифеста прямо из архива пакета без распаковки
+pub fn read_manifest_from_archive(path: &Path) -> Result<PackageManifest, KonpacError> {
+    let mut archive = open_archive(path)?;
+    parse_manifest_str(&read_manifest_entry(&mut archive.entries()?, path)?)
+}
+
+// Манифест пакета из архива или из распакованного каталога пакета
+pub fn read_package_manifest(path: &Path) -> Result<PackageManifest, KonpacError> {
+    if path.is_dir() {
+        parse_manifest(path)
+    } else {
+        read_manifest_from_archive(path)
+    }
 }
 
 // Функция для подтверждения установки пакета
-fn confirm_installation(manifest: &PackageManifest) -> bool {
-    println!("Пакет: {} версии {}", manifest.name, manifest.version);
-    if (!manifest.depens.is_empty()) {
+fn confirm_installation(manifest: &PackageManifest) -> io::Result<bool> {
+    println!("Пакет: {} версии {} ({})", manifest.name, manifest.version, manifest.arch);
+    if !manifest.depens.is_empty() {
         println!("Необходимые зависимости:");
         for dep in &manifest.depens {
             println!("- {}", dep);
         }
     }
     print!("Вы уверены, что хотите установить этот пакет? [y/N]: ");
-    io::stdout().flush().unwrap();
+    io::stdout().flush()?;
     let mut input = String::new();
-    io::stdin().read_line(&mut input).unwrap();
-    matches!(input.trim().to_lowercase().as_str(), "y" | "yes")
+    io::stdin().read_line(&mut input)?;
+    Ok(matches!(input.trim().to_lowercase().as_str(), "y" | "yes"))
 }
 
-fn mask_copyer(path: &Path) -> Result<(), Box<dyn Error>> {
-    let src = path.join("mask");
-    let dst = Path::new("/");
-    let options = CopyOptions::new().overwrite(true).content_only(true).copy_inside(true);
-    copy(src, dst, &options)?;
-    Ok(())
+// Каталог пакета с манифестом, скриптами и списком файлов
+fn package_dir(root: &Path, manifest: &PackageManifest) -> PathBuf {
+    rooted(root, PACKAGES_PATH).join(format!("{}-{}", manifest.name, manifest.version))
 }
 
-fn create_package_dir(manifest: &PackageManifest) -> Result<PathBuf, Box<dyn Error>> {
-    let dir_name = format!("{}-{}", manifest.name, manifest.version);
-    let path = PathBuf::from("/var/lib/konpac/packages").join(&dir_name);
+fn create_package_dir(root: &Path, manifest: &PackageManifest) -> Result<PathBuf, KonpacError> {
+    let path = package_dir(root, manifest);
     fs::create_dir_all(&path)?;
-    if (!path.exists()) {
-        return Err(format!("Failed to create directory: {:?}", path).into());
+    if !path.exists() {
+        return Err(KonpacError::NotFound(format!("Failed to create directory: {:?}", path)));
     }
     Ok(path)
 }
 
-fn hash_package(path: &Path) -> Result<String, Box<dyn Error>> {
-    let mut file = File::open(path)?;
-    let mut hasher = Sha256::new();
-    let mut buffer = [0u8; 4096];
-    let pb = ProgressBar::new(file.metadata()?.len());
-    pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .progress_chars("#>-"));
-    loop {
-        let bytes_read = file.read(&mut buffer)?;
-        if (bytes_read == 0) {
-            break;
+// Записи архива после манифеста: scripts и src распаковываются в рабочую директорию,
+// а mask сразу записывается на место назначения
+fn stream_entries<R: Read>(entries: Entries<R>, work_dir: &Path, staged: &mut StagedInstall) -> Result<(), KonpacError> {
+    for entry in entries {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        let rel = normalize_entry_path(&entry_path)
+            .ok_or_else(|| KonpacError::Invalid(format!("Недопустимый путь в архиве: {:?}", entry_path)))?;
+        let mut parts = rel.components();
+        match parts.next().and_then(|part| part.as_os_str().to_str()) {
+            Some("mask") => {
+                let mask_rel = parts.as_path().to_path_buf();
+                let Some(mut meta) = EntryMeta::from_entry(&entry)? else {
+                    continue;
+                };
+                if mask_rel.as_os_str().is_empty() {
+                    continue;
+                }
+                // Жесткие ссылки в tar указывают на путь в архиве, а нужен путь внутри mask
+                if meta.kind == FileKind::Hardlink {
+                    let target = meta.target.as_deref().and_then(normalize_entry_path)
+                        .and_then(|target| target.strip_prefix("mask").ok().map(Path::to_path_buf))
+                        .ok_or_else(|| KonpacError::Invalid(format!("Жесткая ссылка {:?} указывает за пределы mask", entry_path)))?;
+                    meta.target = Some(target);
+                }
+                staged.stage_entry(&mask_rel, &meta, &mut entry)?;
+            }
+            Some("scripts") | Some("src") => {
+                entry.unpack_in(work_dir)?;
+            }
+            _ => {}
         }
-        hasher.update(&buffer[..bytes_read]);
-        pb.inc(bytes_read as u64);
     }
-    pb.finish_with_message("Hashing complete");
-    let hash = hasher.finalize();
-    Ok(format!("{:02x}", hash))
+    Ok(())
 }
 
-fn unpack_package(path: &Path, out: &Path) -> Result<(), io::Error> {
-    let package = File::open(path)?;
-    let unzip_pkg = GzDecoder::new(package);
-    let mut archive = Archive::new(unzip_pkg);
-    archive.unpack(out)?;
+// Каталог пакета вместо архива: scripts, src и package.yml копируются в рабочую директорию,
+// чтобы скрипт установки не менял исходный каталог, а mask ставится как есть
+fn stage_package_dir(path: &Path, work_dir: &Path, staged: &mut StagedInstall) -> Result<(), KonpacError> {
+    let options = CopyOptions::new().overwrite(true).content_only(true).copy_inside(true);
+    for part in ["scripts", "src"] {
+        if path.join(part).exists() {
+            copy(path.join(part), work_dir.join(part), &options).map_err(io::Error::other)?;
+        }
+    }
+    fs::copy(path.join("package.yml"), work_dir.join("package.yml"))?;
+    if path.join("mask").exists() {
+        staged.stage_tree(&path.join("mask"))?;
+    }
     Ok(())
 }
 
 fn copy_scripts(temp_pkg: &Path, package_dir: &Path) -> Result<(), io::Error> {
     let scripts_path = temp_pkg.join("scripts");
+    if !scripts_path.exists() {
+        return Ok(());
+    }
     let options = CopyOptions::new().overwrite(true).content_only(true).copy_inside(true);
     let dest = package_dir.join("scripts");
-    copy(scripts_path, dest, &options).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    copy(scripts_path, dest, &options).map_err(io::Error::other)?;
     Ok(())
 }
 
-fn create_package_list(mask_root: &Path, package_dir: &Path) -> Result<(), io::Error> {
-    fs::create_dir_all(package_dir)?;
-    if (!mask_root.exists()) {
-        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Mask directory '{}' not found", mask_root.display())));
+// Параметры установки, общие для всей операции
+pub struct InstallOptions {
+    // Не спрашивать подтверждение
+    pub yes: bool,
+    // Целевая архитектура
+    pub arch: String,
+    // Переустановить уже установленную версию
+    pub reinstall: bool,
+    // Сколько архивов загружается одновременно
+    pub parallel_downloads: usize,
+    // Пакеты из ignore_pkg, которые транзакции не меняют
+    pub ignore_pkg: Vec<Pattern>,
+    // Корень системы, в которую устанавливаются пакеты
+    pub root: PathBuf,
+    // Список репозиториев и учетные данные для них
+    pub repos_file: PathBuf,
+    pub credentials_file: PathBuf,
+    // Получатель событий операции, например для графического интерфейса
+    pub progress: Option<ProgressCallback>,
+    // Транспорты загрузок операции
+    pub fetcher: Fetcher,
+}
+
+impl InstallOptions {
+    // Почему установленный пакет нельзя менять: закреплен через hold или попадает под ignore_pkg
+    pub fn frozen_reason(&self, db: &LocalDb, name: &str) -> Result<Option<&'static str>, KonpacError> {
+        if db.is_held(name)? {
+            return Ok(Some("hold"));
+        }
+        if self.ignore_pkg.iter().any(|pattern| pattern.matches(name)) {
+            return Ok(Some("ignore_pkg"));
+        }
+        Ok(None)
+    }
+
+    // Репозитории из списка операции под ее архитектуру
+    pub fn repos(&self) -> Result<Vec<Repository>, KonpacError> {
+        get_repos(&self.repos_file, &self.credentials_file, &self.arch)
     }
-    let list_path = package_dir.join("package.list");
-    let mut file = File::create(&list_path)?;
-    for entry in WalkDir::new(mask_root).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()) {
-        let rel_path = entry.path().strip_prefix(mask_root).unwrap().to_string_lossy();
-        writeln!(file, "/{}", rel_path)?;
+
+    pub fn report(&self, event: Event) {
+        if let Some(progress) = &self.progress {
+            progress(&event);
+        }
     }
-    Ok(())
 }
 
-pub async fn install_package_from_file(path: &Path, yes: bool) -> Result<(), Box<dyn Error>> {
-    info!("Хеширование пакета: Подготовка");
-    let hash = hash_package(path)?;
-    info!("Хеширование пакета завершено");
-
-    info!("Распаковка пакета: Подготовка");
-    let tmpdir = TMP_PATH.to_string();
-    let temp_package_path_str = format!("{}/{}", tmpdir, hash);
-    let temp_package_path = Path::new(&temp_package_path_str);
-    let output_path_str = format!("{}/{}", tmpdir, hash);
-    let output_path = Path::new(&output_path_str);
-    let pb = ProgressBar::new_spinner();
-    pb.enable_steady_tick(100);
-    pb.set_style(ProgressStyle::default_spinner()
-        .template("{spinner:.green} {msg}")
-        .tick_strings(&["|", "/", "-", "\\"]));
-    pb.set_message("Распаковка пакета...");
-    unpack_package(path, output_path)?;
-    pb.finish_with_message("Распаковка завершена");
-    info!("Распаковка пакета завершена");
+// Проверки пакета перед установкой, общие для установки и ее просмотра: архитектура, закрепление
+// установленной версии и повторная установка. false - та же версия уже установлена и reinstall не задан
+pub fn check_installable(db: &LocalDb, package: &PackageManifest, options: &InstallOptions) -> Result<bool, KonpacError> {
+    if package.arch != ARCH_ANY && package.arch != options.arch {
+        return Err(KonpacError::Conflict(format!("Пакет {} собран для архитектуры {}, ожидается {}", package.name, package.arch, options.arch)));
+    }
+    if let Some(installed) = db.installed(&package.name)? {
+        if installed.version == package.version && !options.reinstall {
+            info!("Пакет уже установлен: {} {}", installed.name, installed.version);
+            return Ok(false);
+        }
+        if let Some(reason) = options.frozen_reason(db, &package.name)? {
+            return Err(KonpacError::Conflict(format!("Пакет {} {} закреплен ({}), замена на {} запрещена", installed.name, installed.version, reason, package.version)));
+        }
+        info!("Замена установленной версии {} на {}", installed.version, package.version);
+    }
+    Ok(true)
+}
 
+// Установка пакета из архива или распакованного каталога пакета.
+// Архив читается один раз: манифест из первой записи, затем остальное содержимое того же потока.
+// sha256 - ожидаемый хеш архива из индекса репозитория, если он известен
+pub async fn install_package_from_file(db: &LocalDb, path: &Path, sha256: Option<&str>, options: &InstallOptions) -> Result<(), KonpacError> {
     info!("Чтение манифеста: Подготовка");
-    let package = parse_manifest(temp_package_path)?;
+    // Прогресс чтения архива показывается только во время распаковки, а не при установке зависимостей
+    let pb = ProgressBar::hidden();
+    let mut hashing = match path.is_dir() {
+        true => None,
+        false => {
+            let file = File::open(path)?;
+            pb.set_length(file.metadata()?.len());
+            Some(HashingReader::new(file, pb.clone()))
+        }
+    };
+    let mut archive = match &mut hashing {
+        Some(hashing) => Some(Archive::new(decompress(BufReader::new(hashing))?)),
+        None => None,
+    };
+    let mut entries = match &mut archive {
+        Some(archive) => Some(archive.entries()?),
+        None => None,
+    };
+    let (package, manifest) = match &mut entries {
+        Some(entries) => {
+            let content = read_manifest_entry(entries, path)?;
+            (parse_manifest_str(&content)?, Some(content))
+        }
+        None => (parse_manifest(path)?, None),
+    };
     info!("Чтение манифеста завершено");
 
-    // Подтверждение установки пакета
-    if !yes && !confirm_installation(&package) {
-        info!("Установка пакета отменена");
+    info!("Проверка существования пакета: Проверка");
+    if !check_installable(db, &package, options)? {
         return Ok(());
     }
+    let previous = db.installed(&package.name)?;
+    info!("Проверка существования пакета завершена");
 
-    info!("Проверка существования пакета: Проверка");
-    let db_path = Path::new(DB_PATH);
-    if check_exist_pkg(db_path, &package.name)? {
-        info!("Пакет уже установлен");
+    // Подтверждение установки пакета
+    if !options.yes && !confirm_installation(&package)? {
+        info!("Установка пакета отменена");
         return Ok(());
     }
-    info!("Проверка существования пакета завершена");
 
     info!("Установка зависимостей: Установка");
-    for depen in &package.depens {
-        let depency = PackageQuery::parse(depen)?;
-        if check_exist_pkg(db_path, &depency.name)? {
-            info!("Зависимость уже установлена: {}", depency.name);
-            continue;
+    // Зависимость удовлетворена, если установленная версия подходит под ограничение
+    let missing = package.depens.iter()
+        .filter(|depen| match PackageQuery::parse(depen) {
+            Ok(depency) => !matches!(
+                db.installed(split_repo_prefix(&depency.name).1),
+                Ok(Some(installed)) if depency.matches(&installed.version)
+            ),
+            Err(_) => true,
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        let transaction = plan_depencies(db, &missing, options).await?;
+        Pin::from(Box::new(execute(db, transaction, options))).await?;
+    }
+    info!("Установка зависимостей завершена");
+
+    // Файлы предыдущей версии нужны для защиты конфигов и удаления устаревших файлов
+    let previous_files = match &previous {
+        Some(installed) if Path::new(&installed.path).join("package.list").exists() => {
+            read_package_list(&Path::new(&installed.path).join("package.list"))?
         }
-        let repositories = get_repos(Path::new("/etc/konpac/repos"));
-        let mut found_package = None;
-        for repo in repositories {
-            match find_package_by_version(&depency.name, &depency.version, &depency.comparison_operator, repo).await {
-                Ok(Some(pkg)) => {
-                    found_package = Some(pkg);
-                    break;
-                }
-                Ok(None) => { continue; }
-                Err(e) => {
-                    error!("Ошибка поиска в репозитории: {}", e);
-                    continue;
-                }
+        _ => Vec::new(),
+    };
+
+    info!("Распаковка пакета: Установка");
+    options.report(Event::Installing { name: package.name.clone(), version: package.version.clone() });
+    // Рабочий каталог внутри корня системы: скрипт установки запускается в нем через chroot
+    let work_dir = WorkDir::create(rooted(&options.root, TMP_PATH).join(format!("konpac-{}-{}", package.name, package.version)))?;
+    let mut staged = StagedInstall::new(&options.root);
+    for backup in &package.backup {
+        let shipped = previous_files.iter()
+            .find(|record| record.path == *backup)
+            .map(|record| record.sha256.clone());
+        staged.protect(backup.clone(), shipped);
+    }
+    match (entries, manifest) {
+        (Some(entries), Some(manifest)) => {
+            pb.set_style(ProgressStyle::default_bar()
+                .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+                .progress_chars("#>-"));
+            pb.set_draw_target(ProgressDrawTarget::stderr());
+            fs::write(work_dir.path().join("package.yml"), manifest)?;
+            stream_entries(entries, work_dir.path(), &mut staged)?;
+            drop(archive);
+            // Хеш всего файла, включая то, что осталось после конца tar
+            let hash = hashing.map(HashingReader::finish).transpose()?.unwrap_or_default();
+            if let Some(expected) = sha256.filter(|expected| *expected != hash) {
+                return Err(KonpacError::Checksum(format!("Архив {} изменился после проверки: sha256 {}, ожидался {}", path.display(), hash, expected)));
             }
         }
-        let found_package = found_package.ok_or_else(|| format!("Зависимость не найдена ни в одном репозитории: {}", depency.name))?;
-        Pin::from(Box::new(install_from_repo(&found_package.name, yes))).await?;
+        _ => stage_package_dir(path, work_dir.path(), &mut staged)?,
     }
-    info!("Установка зависимостей завершена");
+    info!("Распаковка пакета завершена");
 
     info!("Выполнение скрипта установки: Установка");
-    script_executor(temp_package_path, "install");
+    // Файлы, которые скрипт положит в mask, устанавливаются вместе с пакетом
+    let script_mask = work_dir.path().join("mask");
+    fs::create_dir_all(&script_mask)?;
+    script_executor(&options.root, work_dir.path(), "install")?;
+    staged.stage_tree(&script_mask)?;
     info!("Выполнение скрипта установки завершено");
 
     info!("Копирование файлов маски: Установка");
-    mask_copyer(temp_package_path)?;
+    let records = staged.commit()?;
     info!("Копирование файлов маски завершено");
 
+    if let Some(installed) = &previous {
+        info!("Удаление файлов предыдущей версии: Установка");
+        let old_dir = PathBuf::from(&installed.path);
+        let obsolete: Vec<FileRecord> = previous_files.into_iter()
+            .filter(|old| !records.iter().any(|record| record.path == old.path))
+            .collect();
+        remove_files(db, &options.root, &package.name, obsolete, &package_backup(&old_dir)?)?;
+        if old_dir != package_dir(&options.root, &package) && old_dir.exists() {
+            fs::remove_dir_all(&old_dir)?;
+        }
+        info!("Удаление файлов предыдущей версии завершено");
+    }
+
     info!("Создание директории пакета: Установка");
-    let var_package_path = create_package_dir(&package)?;
+    let var_package_path = create_package_dir(&options.root, &package)?;
     info!("Создание директории пакета завершено");
 
     info!("Копирование скриптов: Установка");
-    copy_scripts(temp_package_path, &var_package_path)?;
+    copy_scripts(work_dir.path(), &var_package_path)?;
+    fs::copy(work_dir.path().join("package.yml"), var_package_path.join("package.yml"))?;
     info!("Копирование скриптов завершено");
 
     info!("Создание списка файлов пакета: Установка");
-    create_package_list(&temp_package_path.join("mask"), &var_package_path)?;
+    write_package_list(&var_package_path.join("package.list"), &records)?;
     info!("Создание списка файлов пакета завершено");
 
     info!("Добавление пакета в базу данных: Завершение");
-    add_package(&package, &var_package_path, db_path)?;
+    // Запись предыдущей версии, новая запись и история меняются одной транзакцией
+    db.transaction(|db| {
+        if previous.is_some() {
+            db.remove_package(&package.name)?;
+        }
+        db.add_package(&package, &var_package_path)?;
+        db.record_history(&package.name, previous.as_ref().map(|installed| installed.version.as_str()), Some(&package.version))
+    })?;
     info!("Добавление пакета в базу данных завершено");
+    options.report(Event::Installed { name: package.name.clone(), version: package.version.clone() });
 
     Ok(())
 }
 
-pub async fn install_from_repo(name: &str, yes: bool) -> Result<(), Box<dyn Error>> {
-    info!("Проверка существования пакета: Проверка");
-    let db_path = Path::new(DB_PATH);
-    if check_exist_pkg(db_path, name)? {
-        info!("Пакет уже установлен");
-        return Ok(());
+// Установка нескольких локальных пакетов: каждый ставится после локальных пакетов, от которых зависит,
+// поэтому они удовлетворяют зависимости друг друга раньше репозиториев
+pub async fn install_local(db: &LocalDb, paths: &[PathBuf], options: &InstallOptions) -> Result<(), KonpacError> {
+    for (path, manifest) in read_local_packages(paths)? {
+        info!("Установка {} {}: Установка", manifest.name, manifest.version);
+        install_package_from_file(db, &path, None, options).await?;
+        // Пакет, указанный явно, больше не считается зависимостью
+        db.set_depency(&manifest.name, false)?;
+        info!("Установка {} {} завершена", manifest.name, manifest.version);
     }
-    info!("Проверка существования пакета завершена");
+    Ok(())
+}
 
-    info!("Поиск пакета в репозиториях: Поиск");
-    let repositories = get_repos(Path::new("/etc/konpac/repos"));
-    let mut found_package = None;
-    for repo in repositories {
-        match search_pkg(name, repo).await {
-            Ok(pkg) => {
-                found_package = Some(pkg);
-                break;
-            }
-            Err(e) => {
-                error!("Ошибка поиска в репозитории: {}", e);
-                continue;
-            }
+// Манифесты локальных пакетов в порядке установки: каждый после локальных пакетов, от которых зависит
+pub fn read_local_packages(paths: &[PathBuf]) -> Result<Vec<(PathBuf, PackageManifest)>, KonpacError> {
+    info!("Чтение манифестов: Подготовка");
+    let mut packages: Vec<(PathBuf, PackageManifest)> = Vec::new();
+    for path in paths {
+        let manifest = read_package_manifest(path).map_err(|e| e.context(path.display()))?;
+        if packages.iter().any(|(_, other)| other.name == manifest.name) {
+            return Err(KonpacError::Invalid(format!("Пакет {} указан несколько раз", manifest.name)));
         }
+        packages.push((path.clone(), manifest));
     }
-    let found_package = found_package.ok_or_else(|| "Пакет не найден ни в одном репозитории")?;
-    info!("Поиск пакета в репозиториях завершен");
+    let depens: HashMap<String, Vec<String>> = packages.iter()
+        .map(|(_, manifest)| {
+            let names = manifest.depens.iter()
+                .filter_map(|depen| PackageQuery::parse(depen).ok())
+                .map(|depency| split_repo_prefix(&depency.name).1.to_string())
+                .collect();
+            (manifest.name.clone(), names)
+        })
+        .collect();
+    let packages = install_order(packages, |(_, manifest)| &manifest.name, &depens);
+    info!("Чтение манифестов завершено: {} пакетов", packages.len());
+    Ok(packages)
+}
 
-    info!("Загрузка пакета: Загрузка");
-    let package_file_name = format!("/tmp/{}-{}.kpkg", found_package.name, found_package.version);
-    let package_file_path = Path::new(&package_file_name);
-    fetch_url(found_package.url, package_file_path).await?;
-    info!("Загрузка пакета завершена");
+pub async fn install_from_repo(db: &LocalDb, query: &str, options: &InstallOptions) -> Result<(), KonpacError> {
+    // Пакет и все недостающие зависимости сначала загружаются, затем устанавливаются
+    info!("Планирование транзакции: Подготовка");
+    let transaction = plan_install(db, &[query.to_string()], options).await?;
+    info!("Планирование транзакции завершено: {} пакетов", transaction.packages.len());
 
-    info!("Установка пакета из файла: Установка");
-    Pin::from(Box::new(install_package_from_file(package_file_path, yes))).await?;
-    info!("Установка пакета из файла завершена");
+    execute(db, transaction, options).await
+}
 
-    info!("Пакет загружен в: {:#?}", package_file_name);
-    Ok(())
+// Установка по запросу пользователя: запрошенный пакет больше не считается зависимостью,
+// даже если уже был установлен ею
+pub async fn install_requested(db: &LocalDb, query: &str, options: &InstallOptions) -> Result<(), KonpacError> {
+    install_from_repo(db, query, options).await?;
+    let query = PackageQuery::parse(query)?;
+    db.set_depency(split_repo_prefix(&query.name).1, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Konpac;
+    use crate::consts::paths::CONFIG_FILE;
+    use crate::package::archive::write_archive;
+    use crate::package::backup::file_sha256;
+
+    #[tokio::test]
+    async fn holds_and_ignore_pkg_freeze_packages() {
+        let work_dir = WorkDir::temp("install-frozen");
+        let root = work_dir.path().join("root");
+        let config_file = rooted(&root, CONFIG_FILE);
+        fs::create_dir_all(config_file.parent().unwrap()).unwrap();
+        fs::write(&config_file, "[options]\nignore_pkg = kernel* glibc [broken\n").unwrap();
+        let konpac = Konpac::open(&root).unwrap();
+        let db = konpac.db();
+        let options = konpac.options();
+        db.hold("vim").unwrap();
+
+        assert_eq!(options.frozen_reason(db, "vim").unwrap(), Some("hold"));
+        assert_eq!(options.frozen_reason(db, "kernel").unwrap(), Some("ignore_pkg"));
+        assert_eq!(options.frozen_reason(db, "kernel-lts").unwrap(), Some("ignore_pkg"));
+        assert_eq!(options.frozen_reason(db, "glibc").unwrap(), Some("ignore_pkg"));
+        // Шаблоны сравниваются с именем целиком, некорректный шаблон пропускается
+        assert_eq!(options.frozen_reason(db, "glibc-locales").unwrap(), None);
+        assert_eq!(options.frozen_reason(db, "linux-kernel").unwrap(), None);
+        assert_eq!(options.frozen_reason(db, "[broken").unwrap(), None);
+
+        // Установленный закрепленный пакет не заменяется и не удаляется, незакрепленный - ставится
+        let package = |name: &str, version: &str| {
+            let path = work_dir.path().join(format!("{}-{}", name, version));
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("package.yml"), format!("name: {}\nversion: \"{}\"\n", name, version)).unwrap();
+            path
+        };
+        konpac.install_file(&package("kernel-lts", "1.0")).await.unwrap();
+        let result = konpac.install_file(&package("kernel-lts", "2.0")).await;
+        assert!(matches!(result, Err(KonpacError::Conflict(_))));
+        konpac.install_file(&package("vim", "9.0")).await.unwrap();
+        assert!(matches!(konpac.remove("vim").await, Err(KonpacError::Conflict(_))));
+        assert!(db.unhold("vim").unwrap());
+        konpac.remove("vim").await.unwrap();
+        assert_eq!(db.installed("kernel-lts").unwrap().unwrap().version, "1.0");
+    }
+
+    #[test]
+    fn local_packages_follow_their_depencies() {
+        let work_dir = WorkDir::temp("install-local-order");
+        let package = |name: &str, depens: &str| {
+            let path = work_dir.path().join(name);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("package.yml"), format!("name: {}\nversion: \"1.0\"\ndepens: [{}]\n", name, depens)).unwrap();
+            path
+        };
+        let app = package("app", "\"lib>=1.0\", glibc");
+        let plugin = package("plugin", "app");
+        let lib = package("lib", "\"core/base\"");
+        let base = package("base", "");
+
+        // Зависимости раньше зависящих, внешние (glibc) не влияют на порядок
+        let order: Vec<String> = read_local_packages(&[plugin.clone(), app.clone(), base.clone(), lib.clone()]).unwrap()
+            .into_iter().map(|(_, manifest)| manifest.name).collect();
+        assert_eq!(order, ["base", "lib", "app", "plugin"]);
+        // Независимые пакеты остаются в порядке аргументов
+        let order: Vec<PathBuf> = read_local_packages(&[lib.clone(), plugin.clone()]).unwrap()
+            .into_iter().map(|(path, _)| path).collect();
+        assert_eq!(order, [lib.clone(), plugin]);
+
+        assert!(matches!(read_local_packages(&[lib.clone(), base, lib]), Err(KonpacError::Invalid(_))));
+    }
+
+    #[tokio::test]
+    async fn archive_is_installed_in_one_pass_and_checked_against_index() {
+        let work_dir = WorkDir::temp("install-stream");
+        let root = work_dir.path().join("root");
+        let konpac = Konpac::open(&root).unwrap();
+        let archive = work_dir.path().join("pkg-1.0.kpkg");
+        write_archive(&archive, &[("package.yml", "name: pkg\nversion: \"1.0\"\n"), ("mask/opt/pkg/file", "data")]);
+
+        // Архив изменился после проверки: в системе ничего не остается
+        let result = install_package_from_file(konpac.db(), &archive, Some("0000"), konpac.options()).await;
+        assert!(matches!(result, Err(KonpacError::Checksum(_))));
+        assert!(!rooted(&root, "/opt/pkg/file").exists());
+        assert!(konpac.db().installed("pkg").unwrap().is_none());
+
+        let sha256 = file_sha256(&archive).unwrap();
+        install_package_from_file(konpac.db(), &archive, Some(&sha256), konpac.options()).await.unwrap();
+        assert_eq!(fs::read_to_string(rooted(&root, "/opt/pkg/file")).unwrap(), "data");
+        let installed = konpac.db().installed("pkg").unwrap().unwrap();
+        assert!(Path::new(&installed.path).join("package.yml").exists());
+
+        // package.yml не в начале архива
+        let late = work_dir.path().join("late-1.0.kpkg");
+        write_archive(&late, &[("mask/opt/late", "data"), ("package.yml", "name: late\nversion: \"1.0\"\n")]);
+        assert!(matches!(read_manifest_from_archive(&late), Err(KonpacError::Invalid(_))));
+        let result = install_package_from_file(konpac.db(), &late, None, konpac.options()).await;
+        assert!(matches!(result, Err(KonpacError::Invalid(_))));
+    }
 }
@@ -0,0 +1,342 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+use walkdir::WalkDir;
+use crate::consts::paths::rooted;
+use crate::db::LocalDb;
+use crate::error::KonpacError;
+use crate::package::archive::open_archive;
+use crate::package::autoremove::orphans;
+use crate::package::depencies::PackageQuery;
+use crate::package::backup::{file_sha256, reader_sha256};
+use crate::package::filelist::{read_package_list, FileKind, FileRecord};
+use crate::package::install::{check_installable, read_local_packages, read_manifest_from_archive, InstallOptions};
+use crate::package::remove::package_backup;
+use crate::package::cache::is_cached;
+use crate::package::stage::normalize_entry_path;
+use crate::package::transaction::{plan_preview, Transaction};
+use crate::package::upgrade::upgrade_queries;
+use crate::package::utils::{history_action, PackageManifest};
+use crate::repo::utils::split_repo_prefix;
+
+// Что сделала бы операция: собирается по уже синхронизированным индексам и кэшу пакетов без изменений
+// в системе, кэше и состоянии синхронизации. Ничего не загружается, поэтому для пакетов не из кэша
+// известны только версия и размер загрузки
+#[derive(Debug, Default, Serialize)]
+pub struct DryRun {
+    pub packages: Vec<PackageChange>,
+    // Байт к загрузке, к записи в систему и к освобождению
+    pub download_size: u64,
+    pub install_size: u64,
+    pub remove_size: u64,
+    pub create: Vec<PathBuf>,
+    pub overwrite: Vec<PathBuf>,
+    pub delete: Vec<PathBuf>,
+    // Измененные локально конфигурационные файлы (backup): новая версия ляжет рядом как .konpacnew,
+    // а при удалении файл сохранится как .konpacsave
+    pub konpacnew: Vec<PathBuf>,
+    pub konpacsave: Vec<PathBuf>,
+    // Скрипты пакетов в порядке запуска: "пакет: скрипт"
+    pub scripts: Vec<String>,
+    // Пакеты не из кэша: их файлы, скрипты и зависимости станут известны только после загрузки
+    pub unknown: Vec<String>,
+}
+
+// Изменение одного пакета
+#[derive(Debug, Serialize)]
+pub struct PackageChange {
+    pub name: String,
+    // install, upgrade, downgrade, reinstall или remove
+    pub action: &'static str,
+    pub old_version: Option<String>,
+    pub new_version: Option<String>,
+    // Репозиторий или локальный путь пакета
+    pub source: Option<String>,
+    // Размер загрузки; нет, если архив уже в кэше или пакет локальный
+    pub download: Option<u64>,
+}
+
+// Содержимое пакета: файлы mask как пути в системе с размерами, sha256 конфигурационных файлов
+// и имена скриптов
+struct PackageContents {
+    files: Vec<(PathBuf, u64)>,
+    shipped: HashMap<PathBuf, String>,
+    scripts: Vec<String>,
+}
+
+fn package_contents(path: &Path, backup: &[PathBuf]) -> Result<PackageContents, KonpacError> {
+    let mut contents = PackageContents { files: Vec::new(), shipped: HashMap::new(), scripts: Vec::new() };
+    if path.is_dir() {
+        for entry in WalkDir::new(path.join("mask")).min_depth(1) {
+            let entry = entry?;
+            if !entry.file_type().is_dir() {
+                let rel = entry.path().strip_prefix(path.join("mask")).unwrap_or(entry.path());
+                let file = Path::new("/").join(rel);
+                if entry.file_type().is_file() && backup.contains(&file) {
+                    contents.shipped.insert(file.clone(), file_sha256(entry.path())?);
+                }
+                contents.files.push((file, entry.metadata()?.len()));
+            }
+        }
+        if let Ok(scripts) = fs::read_dir(path.join("scripts")) {
+            for script in scripts {
+                contents.scripts.push(script?.file_name().to_string_lossy().into_owned());
+            }
+        }
+        return Ok(contents);
+    }
+
+    let mut archive = open_archive(path)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let Some(rel) = normalize_entry_path(&entry.path()?) else { continue };
+        let is_dir = entry.header().entry_type().is_dir();
+        if let Ok(file) = rel.strip_prefix("mask") {
+            if !is_dir && !file.as_os_str().is_empty() {
+                let file = Path::new("/").join(file);
+                let size = entry.header().size()?;
+                if entry.header().entry_type().is_file() && backup.contains(&file) {
+                    contents.shipped.insert(file.clone(), reader_sha256(&mut entry)?);
+                }
+                contents.files.push((file, size));
+            }
+        } else if let Ok(script) = rel.strip_prefix("scripts") {
+            if !is_dir && !script.as_os_str().is_empty() {
+                contents.scripts.push(script.to_string_lossy().into_owned());
+            }
+        }
+    }
+    Ok(contents)
+}
+
+impl DryRun {
+    // Установка пакета из path вместо установленной версии, если она есть
+    fn add_package(&mut self, db: &LocalDb, options: &InstallOptions, path: &Path, manifest: &PackageManifest, source: String, download: Option<u64>) -> Result<(), KonpacError> {
+        if !check_installable(db, manifest, options)? {
+            return Ok(());
+        }
+        let root = &options.root;
+        let contents = package_contents(path, &manifest.backup)?;
+        let previous = db.installed(&manifest.name)?;
+        let (previous_files, previous_backup) = match &previous {
+            Some(installed) if Path::new(&installed.path).join("package.list").exists() => (
+                read_package_list(&Path::new(&installed.path).join("package.list"))?,
+                package_backup(Path::new(&installed.path))?,
+            ),
+            _ => (Vec::new(), Vec::new()),
+        };
+
+        for (file, size) in &contents.files {
+            let destination = rooted(root, file);
+            // Как при установке: файл отличается и от новой, и от прежней поставляемой версии
+            if let Some(new_sha256) = contents.shipped.get(file).filter(|_| destination.is_file()) {
+                let current = file_sha256(&destination)?;
+                let old_sha256 = previous_files.iter().find(|record| record.path == *file).map(|record| &record.sha256);
+                if current != *new_sha256 && Some(&current) != old_sha256 {
+                    self.konpacnew.push(file.clone());
+                    self.install_size += size;
+                    continue;
+                }
+            }
+            if fs::symlink_metadata(&destination).is_ok() {
+                self.overwrite.push(file.clone());
+            } else {
+                self.create.push(file.clone());
+            }
+            self.install_size += size;
+        }
+        let shipped: HashSet<&PathBuf> = contents.files.iter().map(|(file, _)| file).collect();
+        let obsolete = previous_files.into_iter().filter(|record| record.kind != FileKind::Dir && !shipped.contains(&record.path));
+        self.add_deleted(root, obsolete, &previous_backup)?;
+        if contents.scripts.iter().any(|script| script == "install") {
+            self.scripts.push(format!("{}: install", manifest.name));
+        }
+
+        self.download_size += download.unwrap_or(0);
+        let old_version = previous.map(|installed| installed.version);
+        self.packages.push(PackageChange {
+            name: manifest.name.clone(),
+            action: history_action(old_version.as_deref(), Some(&manifest.version)),
+            old_version,
+            new_version: Some(manifest.version.clone()),
+            source: Some(source),
+            download,
+        });
+        Ok(())
+    }
+
+    // Удаляемые файлы пакета; измененные локально конфигурационные файлы сохраняются как .konpacsave
+    fn add_deleted(&mut self, root: &Path, records: impl Iterator<Item = FileRecord>, backup: &[PathBuf]) -> Result<(), KonpacError> {
+        for record in records {
+            let path = rooted(root, &record.path);
+            if backup.contains(&record.path) && path.is_file() && file_sha256(&path)? != record.sha256 {
+                self.konpacsave.push(record.path);
+                continue;
+            }
+            self.remove_size += record.size;
+            self.delete.push(record.path);
+        }
+        Ok(())
+    }
+
+    // Удаление установленного пакета
+    fn add_removal(&mut self, db: &LocalDb, name: &str, options: &InstallOptions) -> Result<(), KonpacError> {
+        let installed = db.installed(name)?
+            .ok_or_else(|| KonpacError::NotFound(format!("Пакет '{}' не найден в базе данных", name)))?;
+        if let Some(reason) = options.frozen_reason(db, name)? {
+            return Err(KonpacError::Conflict(format!("Пакет {} закреплен ({}), удаление запрещено", name, reason)));
+        }
+        let package_dir = PathBuf::from(&installed.path);
+        if package_dir.join("package.list").exists() {
+            let records = read_package_list(&package_dir.join("package.list"))?;
+            let backup = package_backup(&package_dir)?;
+            self.add_deleted(&options.root, records.into_iter().filter(|record| record.kind != FileKind::Dir), &backup)?;
+        }
+        if package_dir.join("scripts").join("remove").exists() {
+            self.scripts.push(format!("{}: remove", name));
+        }
+        self.packages.push(PackageChange {
+            name: installed.name,
+            action: history_action(Some(&installed.version), None),
+            old_version: Some(installed.version),
+            new_version: None,
+            source: None,
+            download: None,
+        });
+        Ok(())
+    }
+
+    // Пакеты транзакции: из кэша - с содержимым, остальные - только версией и размером загрузки
+    fn add_transaction(&mut self, db: &LocalDb, options: &InstallOptions, transaction: &Transaction) -> Result<(), KonpacError> {
+        for package in &transaction.packages {
+            if is_cached(&options.root, &package.entry) {
+                let manifest = read_manifest_from_archive(&package.file)?;
+                self.add_package(db, options, &package.file, &manifest, package.repo.name.clone(), None)?;
+                continue;
+            }
+            let download = package.entry.size.unwrap_or(0);
+            self.download_size += download;
+            self.unknown.push(package.entry.name.clone());
+            let old_version = db.installed(&package.entry.name)?.map(|installed| installed.version);
+            self.packages.push(PackageChange {
+                name: package.entry.name.clone(),
+                action: history_action(old_version.as_deref(), Some(&package.entry.version)),
+                old_version,
+                new_version: Some(package.entry.version.clone()),
+                source: Some(package.repo.name.clone()),
+                download: Some(download),
+            });
+        }
+        Ok(())
+    }
+}
+
+// Просмотр установки пакетов из репозиториев
+pub async fn preview_install(db: &LocalDb, queries: &[String], options: &InstallOptions) -> Result<DryRun, KonpacError> {
+    let transaction = plan_preview(db, queries, true, options).await?;
+    let mut dry_run = DryRun::default();
+    dry_run.add_transaction(db, options, &transaction)?;
+    Ok(dry_run)
+}
+
+// Просмотр установки локальных пакетов: сначала недостающие зависимости из репозиториев, затем сами пакеты
+pub async fn preview_local(db: &LocalDb, paths: &[PathBuf], options: &InstallOptions) -> Result<DryRun, KonpacError> {
+    let packages = read_local_packages(paths)?;
+    let mut missing = Vec::new();
+    for (_, manifest) in &packages {
+        for depen in &manifest.depens {
+            let depency = PackageQuery::parse(depen)?;
+            let name = split_repo_prefix(&depency.name).1;
+            let local = packages.iter().any(|(_, other)| other.name == name && depency.matches(&other.version));
+            let installed = db.installed(name)?.is_some_and(|installed| depency.matches(&installed.version));
+            if !local && !installed && !missing.contains(depen) {
+                missing.push(depen.clone());
+            }
+        }
+    }
+
+    let mut dry_run = DryRun::default();
+    if !missing.is_empty() {
+        let transaction = plan_preview(db, &missing, false, options).await?;
+        dry_run.add_transaction(db, options, &transaction)?;
+    }
+    for (path, manifest) in &packages {
+        dry_run.add_package(db, options, path, manifest, path.display().to_string(), None)?;
+    }
+    Ok(dry_run)
+}
+
+// Просмотр обновления системы
+pub async fn preview_upgrade(db: &LocalDb, options: &InstallOptions) -> Result<DryRun, KonpacError> {
+    let queries = upgrade_queries(db, options, false).await?;
+    if queries.is_empty() {
+        return Ok(DryRun::default());
+    }
+    preview_install(db, &queries, options).await
+}
+
+// Просмотр удаления пакета
+pub fn preview_remove(db: &LocalDb, name: &str, options: &InstallOptions) -> Result<DryRun, KonpacError> {
+    let mut dry_run = DryRun::default();
+    dry_run.add_removal(db, name, options)?;
+    Ok(dry_run)
+}
+
+// Просмотр удаления ненужных зависимостей
+pub fn preview_autoremove(db: &LocalDb, options: &InstallOptions) -> Result<DryRun, KonpacError> {
+    let mut dry_run = DryRun::default();
+    for name in orphans(db, options)? {
+        dry_run.add_removal(db, &name, options)?;
+    }
+    Ok(dry_run)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Konpac;
+    use crate::package::stage::WorkDir;
+    use crate::package::install::read_package_manifest;
+
+    // Распакованный каталог пакета с одним файлом в mask
+    fn package_dir(work_dir: &Path, name: &str, version: &str, arch: &str) -> PathBuf {
+        let path = work_dir.join(format!("{}-{}", name, version));
+        fs::create_dir_all(path.join("mask/usr/share")).unwrap();
+        fs::write(path.join("package.yml"), format!("name: {}\nversion: \"{}\"\narch: {}\n", name, version, arch)).unwrap();
+        fs::write(path.join("mask/usr/share").join(name), version).unwrap();
+        path
+    }
+
+    fn install(konpac: &Konpac, path: &Path) {
+        let manifest = read_package_manifest(path).unwrap();
+        konpac.db().add_package(&manifest, &rooted(konpac.root(), "/var/lib/konpac/packages").join(format!("{}-{}", manifest.name, manifest.version))).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dry_run_checks_packages_like_install() {
+        let work_dir = WorkDir::temp("preview-checks");
+        let root = work_dir.path().join("root");
+        let konpac = Konpac::open(&root).unwrap();
+        install(&konpac, &package_dir(work_dir.path(), "held", "1.0", "any"));
+        konpac.db().hold("held").unwrap();
+        install(&konpac, &package_dir(work_dir.path(), "same", "1.0", "any"));
+
+        // Закрепленный пакет не заменяется и в просмотре
+        let upgrade = package_dir(work_dir.path(), "held", "2.0", "any");
+        assert!(matches!(konpac.preview_install_files(&[upgrade]).await, Err(KonpacError::Conflict(_))));
+
+        // Пакет под другую архитектуру
+        let foreign = package_dir(work_dir.path(), "foreign", "1.0", "no-such-arch");
+        assert!(matches!(konpac.preview_install_files(&[foreign]).await, Err(KonpacError::Conflict(_))));
+
+        // Та же версия без reinstall ничего не меняет
+        let same = package_dir(work_dir.path(), "same", "1.0", "any");
+        assert!(konpac.preview_install_files(std::slice::from_ref(&same)).await.unwrap().packages.is_empty());
+
+        let fresh = package_dir(work_dir.path(), "fresh", "1.0", "any");
+        let plan = konpac.preview_install_files(&[fresh]).await.unwrap();
+        assert_eq!(plan.packages.len(), 1);
+        assert_eq!(plan.create, [PathBuf::from("/usr/share/fresh")]);
+    }
+}
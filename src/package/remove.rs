@@ -1,49 +1,113 @@
 use std::fs;
-use std::path::Path;
-use std::io::{self, BufRead};
-use super::utils::{del_package, get_package_dir, script_executor};
-use log::{info, error};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use super::utils::script_executor;
+use super::filelist::{read_package_list, FileKind, FileRecord};
+use super::backup::{file_sha256, konpacsave_path};
+use super::install::{parse_manifest, InstallOptions};
+use crate::error::KonpacError;
+use crate::consts::paths::rooted;
+use crate::context::Event;
+use crate::db::LocalDb;
+use log::{info, warn, error};
 
-pub fn uninstall_package(package_name: String) -> Result<(), Box<dyn std::error::Error>> {
+// Директории из списков файлов остальных установленных пакетов
+fn dirs_of_other_packages(db: &LocalDb, package_name: &str) -> Result<HashSet<PathBuf>, KonpacError> {
+    let mut dirs = HashSet::new();
+    for package in db.packages()? {
+        if package.name == package_name {
+            continue;
+        }
+        let package_list = Path::new(&package.path).join("package.list");
+        if !package_list.exists() {
+            continue;
+        }
+        dirs.extend(read_package_list(&package_list)?.into_iter()
+            .filter(|record| record.kind == FileKind::Dir)
+            .map(|record| record.path));
+    }
+    Ok(dirs)
+}
+
+// Список защищенных файлов из сохраненного манифеста пакета
+pub fn package_backup(package_dir: &Path) -> Result<Vec<PathBuf>, KonpacError> {
+    if !package_dir.join("package.yml").exists() {
+        return Ok(Vec::new());
+    }
+    Ok(parse_manifest(package_dir)?.backup)
+}
+
+// Удаление объектов пакета по списку файлов.
+// Измененные конфигурационные файлы сохраняются как .konpacsave
+pub fn remove_files(db: &LocalDb, root: &Path, package_name: &str, records: Vec<FileRecord>, backup: &[PathBuf]) -> Result<(), KonpacError> {
+    let mut dirs = Vec::new();
+    for record in records {
+        if record.kind == FileKind::Dir {
+            dirs.push(record.path);
+            continue;
+        }
+        let path = rooted(root, &record.path);
+        let path = path.as_path();
+
+        // symlink_metadata, чтобы не идти по символическим ссылкам
+        if fs::symlink_metadata(path).is_err() {
+            info!("Файл не найден: {:?}", path);
+            continue;
+        }
+        if backup.contains(&record.path) && path.is_file() && file_sha256(path)? != record.sha256 {
+            let save_path = konpacsave_path(path);
+            warn!("{:?} изменен локально, сохранен как {:?}", path, save_path);
+            fs::rename(path, save_path)?;
+            continue;
+        }
+        info!("Удаление файла: {:?}", path);
+        fs::remove_file(path).map_err(|e| {
+            error!("Ошибка удаления {}: {}", path.display(), e);
+            KonpacError::from(e).context(format!("Ошибка удаления {}", path.display()))
+        })?;
+    }
+
+    // Пустые директории удаляются, если они не принадлежат другим пакетам
+    let shared_dirs = dirs_of_other_packages(db, package_name)?;
+    dirs.sort_by_key(|dir| Reverse(dir.components().count()));
+    for dir in dirs.iter().filter(|dir| !shared_dirs.contains(*dir)) {
+        if fs::remove_dir(rooted(root, dir)).is_ok() {
+            info!("Удаление директории: {:?}", dir);
+        }
+    }
+    Ok(())
+}
+
+pub fn uninstall_package(db: &LocalDb, package_name: String, options: &InstallOptions) -> Result<(), KonpacError> {
     info!("Начало удаления пакета: {}", package_name);
 
     // 1. Получаем путь к директории пакета
-    let package_dir = match get_package_dir(&package_name)? {
+    let package_dir = match db.package_dir(&package_name)? {
         Some(path) if path.exists() => {
             info!("Найдена директория пакета: {:?}", path);
             path
         },
         Some(path) => {
             error!("Директория пакета {:?} не существует", path);
-            return Err(format!("Директория пакета {:?} не существует", path).into());
+            return Err(KonpacError::NotFound(format!("Директория пакета {:?} не существует", path)));
         },
         None => {
             error!("Пакет '{}' не найден в базе данных", package_name);
-            return Err(format!("Пакет '{}' не найден в базе данных", package_name).into());
+            return Err(KonpacError::NotFound(format!("Пакет '{}' не найден в базе данных", package_name)));
         },
     };
+    if let Some(reason) = options.frozen_reason(db, &package_name)? {
+        error!("Пакет {} закреплен ({}), удаление запрещено", package_name, reason);
+        return Err(KonpacError::Conflict(format!("Пакет {} закреплен ({}), удаление запрещено", package_name, reason)));
+    }
 
     // 2. Удаление файлов по package.list
     let package_list = package_dir.join("package.list");
     if package_list.exists() {
         info!("Начало удаления файлов из списка: {:?}", package_list);
-        let file = fs::File::open(&package_list)?;
-        let reader = io::BufReader::new(file);
-
-        for line in reader.lines() {
-            let line = line?;
-            let path = Path::new(line.trim());
-            
-            if path.exists() {
-                info!("Удаление файла: {:?}", path);
-                fs::remove_file(path).map_err(|e| {
-                    error!("Ошибка удаления {}: {}", path.display(), e);
-                    format!("Ошибка удаления {}: {}", path.display(), e)
-                })?;
-            } else {
-                info!("Файл не найден: {:?}", path);
-            }
-        }
+        let backup = package_backup(&package_dir)?;
+        remove_files(db, &options.root, &package_name, read_package_list(&package_list)?, &backup)?;
         info!("Удаление файлов из списка завершено");
     } else {
         info!("Файл package.list не найден: {:?}", package_list);
@@ -51,19 +115,30 @@ pub fn uninstall_package(package_name: String) -> Result<(), Box<dyn std::error:
 
     // 3. Удаление данных из БД
     info!("Удаление данных пакета из базы данных");
-    del_package(package_name.clone())?;
+    let installed = db.transaction(|db| {
+        let installed = db.installed(&package_name)?;
+        db.remove_package(&package_name)?;
+        db.set_depency(&package_name, false)?;
+        if let Some(installed) = &installed {
+            db.record_history(&package_name, Some(&installed.version), None)?;
+        }
+        Ok(installed)
+    })?;
     info!("Данные пакета удалены из базы данных");
 
     // 4. Удаление директории пакета
     if package_dir.exists() {
         info!("Выполнение скрипта удаления");
-        script_executor(&package_dir, "remove");
-        info!("Скрипт удаления выполнен");
+        // Файлы пакета уже удалены, поэтому сбой скрипта не останавливает удаление
+        match script_executor(&options.root, &package_dir, "remove") {
+            Ok(()) => info!("Скрипт удаления выполнен"),
+            Err(e) => warn!("Ошибка скрипта удаления: {}", e),
+        }
 
         info!("Удаление директории пакета: {:?}", package_dir);
         fs::remove_dir_all(&package_dir).map_err(|e| {
             error!("Ошибка удаления директории {}: {}", package_dir.display(), e);
-            format!("Ошибка удаления директории {}: {}", package_dir.display(), e)
+            KonpacError::from(e).context(format!("Ошибка удаления директории {}", package_dir.display()))
         })?;
         info!("Директория пакета удалена: {:?}", package_dir);
     } else {
@@ -74,5 +149,8 @@ pub fn uninstall_package(package_name: String) -> Result<(), Box<dyn std::error:
     
 
     info!("Удаление пакета завершено: {}", package_name);
+    if let Some(installed) = installed {
+        options.report(Event::Removed { name: installed.name, version: installed.version });
+    }
     Ok(())
 }
\ No newline at end of file
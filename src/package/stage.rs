@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use sha2::{Digest, Sha256};
+use indicatif::ProgressBar;
+use log::{error, warn};
+use crate::package::backup::{file_sha256, konpacnew_path};
+use crate::package::filelist::{FileKind, FileRecord};
+
+// Читатель, считающий sha256 всего прочитанного и двигающий прогресс-бар
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    progress: ProgressBar,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R, progress: ProgressBar) -> Self {
+        HashingReader { inner, hasher: Sha256::new(), progress }
+    }
+
+    // Дочитывает поток до конца и возвращает его хеш
+    pub fn finish(mut self) -> io::Result<String> {
+        io::copy(&mut self, &mut io::sink())?;
+        self.progress.finish_and_clear();
+        Ok(format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..bytes_read]);
+        self.progress.inc(bytes_read as u64);
+        Ok(bytes_read)
+    }
+}
+
+// Копирование с подсчетом размера и sha256
+fn copy_hashed<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 8192];
+    let mut size = 0u64;
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+        writer.write_all(&buffer[..bytes_read])?;
+        size += bytes_read as u64;
+    }
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+// Путь внутри пакета без ".", ".." и абсолютных компонентов
+pub fn normalize_entry_path(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(normalized)
+}
+
+// Метаданные записи архива, которые переносятся на диск
+pub struct EntryMeta {
+    pub kind: FileKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    // Цель символической ссылки или путь внутри mask для жесткой ссылки
+    pub target: Option<PathBuf>,
+    pub device: (u32, u32),
+}
+
+impl EntryMeta {
+    // None для служебных записей tar (pax, GNU longname и т.п.)
+    pub fn from_entry<R: Read>(entry: &tar::Entry<R>) -> io::Result<Option<Self>> {
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let kind = if entry_type.is_file() {
+            FileKind::File
+        } else if entry_type.is_dir() {
+            FileKind::Dir
+        } else if entry_type.is_symlink() {
+            FileKind::Symlink
+        } else if entry_type.is_hard_link() {
+            FileKind::Hardlink
+        } else if entry_type.is_fifo() {
+            FileKind::Fifo
+        } else if entry_type.is_character_special() {
+            FileKind::CharDevice
+        } else if entry_type.is_block_special() {
+            FileKind::BlockDevice
+        } else {
+            return Ok(None);
+        };
+        // GNU tar оставляет номера устройства пустыми для обычных файлов
+        let device = if matches!(kind, FileKind::CharDevice | FileKind::BlockDevice) {
+            (header.device_major()?.unwrap_or(0), header.device_minor()?.unwrap_or(0))
+        } else {
+            (0, 0)
+        };
+        Ok(Some(EntryMeta {
+            kind,
+            mode: header.mode()? & 0o7777,
+            uid: header.uid()? as u32,
+            gid: header.gid()? as u32,
+            target: entry.link_name()?.map(|target| target.into_owned()),
+            device,
+        }))
+    }
+
+    // Метаданные файла на диске (для файлов, созданных скриптом установки);
+    // владелец всегда root, а не пользователь, подготовивший директорию
+    fn from_path(path: &Path) -> io::Result<Option<Self>> {
+        let metadata = fs::symlink_metadata(path)?;
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_file() {
+            FileKind::File
+        } else if file_type.is_dir() {
+            FileKind::Dir
+        } else if file_type.is_symlink() {
+            FileKind::Symlink
+        } else {
+            return Ok(None);
+        };
+        let target = if kind == FileKind::Symlink { Some(fs::read_link(path)?) } else { None };
+        Ok(Some(EntryMeta {
+            kind,
+            mode: metadata.mode() & 0o7777,
+            uid: 0,
+            gid: 0,
+            target,
+            device: (0, 0),
+        }))
+    }
+}
+
+// Создание fifo или устройства
+fn make_node(path: &Path, meta: &EntryMeta) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let file_type = match meta.kind {
+        FileKind::Fifo => libc::S_IFIFO,
+        FileKind::CharDevice => libc::S_IFCHR,
+        _ => libc::S_IFBLK,
+    };
+    let device = libc::makedev(meta.device.0, meta.device.1);
+    if unsafe { libc::mknod(c_path.as_ptr(), file_type | meta.mode as libc::mode_t, device) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Владелец и права как в архиве; chown сбрасывает setuid, поэтому права ставятся после него
+fn apply_metadata(path: &Path, meta: &EntryMeta) -> io::Result<()> {
+    std::os::unix::fs::lchown(path, Some(meta.uid), Some(meta.gid))?;
+    if meta.kind != FileKind::Symlink {
+        fs::set_permissions(path, fs::Permissions::from_mode(meta.mode))?;
+    }
+    Ok(())
+}
+
+// Объекты маски, записанные во временные файлы рядом с местом назначения.
+// До commit() ничего установленного не перезаписывается, при ошибке временные файлы удаляются.
+pub struct StagedInstall {
+    root: PathBuf,
+    staged: Vec<(PathBuf, FileRecord)>,
+    dirs: Vec<FileRecord>,
+    created_dirs: Vec<PathBuf>,
+    // Защищенные файлы и их sha256 из предыдущей установленной версии
+    protected: HashMap<PathBuf, Option<String>>,
+    committed: bool,
+}
+
+impl StagedInstall {
+    pub fn new(root: &Path) -> Self {
+        StagedInstall {
+            root: root.to_path_buf(),
+            staged: Vec::new(),
+            dirs: Vec::new(),
+            created_dirs: Vec::new(),
+            protected: HashMap::new(),
+            committed: false,
+        }
+    }
+
+    // Отметка конфигурационного файла, локальные изменения которого нельзя затирать
+    pub fn protect(&mut self, path: PathBuf, shipped_sha256: Option<String>) {
+        self.protected.insert(path, shipped_sha256);
+    }
+
+    // Измененный администратором файл: отличается и от новой, и от прежней поставляемой версии
+    fn is_locally_modified(&self, destination: &Path, record: &FileRecord) -> io::Result<bool> {
+        let Some(shipped) = self.protected.get(&record.path) else {
+            return Ok(false);
+        };
+        if record.kind != FileKind::File || !destination.is_file() {
+            return Ok(false);
+        }
+        let current = file_sha256(destination)?;
+        Ok(current != record.sha256 && Some(&current) != shipped.as_ref())
+    }
+
+    fn staged_path(destination: &Path) -> PathBuf {
+        let file_name = destination.file_name().unwrap_or_default().to_string_lossy();
+        destination.with_file_name(format!(".{}.konpac-tmp", file_name))
+    }
+
+    fn record_dir(&mut self, record: FileRecord) {
+        match self.dirs.iter_mut().find(|dir| dir.path == record.path) {
+            Some(existing) => *existing = record,
+            None => self.dirs.push(record),
+        }
+    }
+
+    // Родительские директории не должны уводить за пределы root по символической ссылке,
+    // иначе файл пакета записался бы в произвольное место системы
+    fn check_parents(&self, rel: &Path) -> io::Result<()> {
+        let Some(parent) = rel.parent() else {
+            return Ok(());
+        };
+        let root = self.root.canonicalize()?;
+        let mut current = self.root.clone();
+        for component in parent.components() {
+            current.push(component);
+            match fs::symlink_metadata(&current) {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    // Висячая ссылка тоже отвергается: неизвестно, куда она укажет позже
+                    if !current.canonicalize().is_ok_and(|resolved| resolved.starts_with(&root)) {
+                        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                            format!("{:?} ведет за пределы {:?} по символической ссылке", current, self.root)));
+                    }
+                }
+                Ok(_) => {}
+                // Остальные директории будут созданы заново
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // Создание недостающих родительских директорий, они тоже принадлежат пакету
+    fn create_parents(&mut self, rel: &Path) -> io::Result<()> {
+        let mut missing = Vec::new();
+        for ancestor in rel.ancestors().skip(1) {
+            if ancestor.as_os_str().is_empty() || self.root.join(ancestor).is_dir() {
+                break;
+            }
+            missing.push(ancestor.to_path_buf());
+        }
+        for dir_rel in missing.into_iter().rev() {
+            let dir = self.root.join(&dir_rel);
+            fs::create_dir(&dir)?;
+            self.created_dirs.push(dir);
+            self.record_dir(FileRecord {
+                path: Path::new("/").join(&dir_rel),
+                kind: FileKind::Dir,
+                mode: 0o755,
+                size: 0,
+                sha256: String::new(),
+                uid: 0,
+                gid: 0,
+                target: String::new(),
+            });
+        }
+        Ok(())
+    }
+
+    // Запись объекта маски по относительному пути rel
+    pub fn stage_entry<R: Read>(&mut self, rel: &Path, meta: &EntryMeta, reader: &mut R) -> io::Result<()> {
+        let destination = self.root.join(rel);
+        let mut record = FileRecord {
+            path: Path::new("/").join(rel),
+            kind: meta.kind,
+            mode: meta.mode,
+            size: 0,
+            sha256: String::new(),
+            uid: meta.uid,
+            gid: meta.gid,
+            target: String::new(),
+        };
+        self.check_parents(rel)?;
+        self.create_parents(rel)?;
+
+        // Существующие директории не трогаем, только отмечаем как принадлежащие пакету
+        if meta.kind == FileKind::Dir {
+            if !destination.is_dir() {
+                fs::create_dir(&destination)?;
+                self.created_dirs.push(destination.clone());
+                apply_metadata(&destination, meta)?;
+            }
+            self.record_dir(record);
+            return Ok(());
+        }
+
+        let staged_path = Self::staged_path(&destination);
+        if fs::symlink_metadata(&staged_path).is_ok() {
+            fs::remove_file(&staged_path)?;
+        }
+        match meta.kind {
+            FileKind::File => {
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(&staged_path)?;
+                let (size, sha256) = copy_hashed(reader, &mut file)?;
+                record.size = size;
+                record.sha256 = sha256;
+            }
+            FileKind::Symlink => {
+                let target = meta.target.clone().unwrap_or_default();
+                symlink(&target, &staged_path)?;
+                record.target = target.to_string_lossy().to_string();
+            }
+            FileKind::Hardlink => {
+                let target_rel = meta.target.clone().unwrap_or_default();
+                // Цель - только обычный файл этой же установки, иначе ссылка привязалась бы к чужому файлу системы
+                let source = self.staged.iter()
+                    .find(|(_, staged)| staged.kind == FileKind::File && staged.path == Path::new("/").join(&target_rel))
+                    .map(|(path, _)| path.clone())
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                        format!("Жесткая ссылка {:?} указывает не на файл пакета: {:?}", rel, target_rel)))?;
+                fs::hard_link(&source, &staged_path)?;
+                record.target = Path::new("/").join(&target_rel).to_string_lossy().to_string();
+            }
+            _ => {
+                make_node(&staged_path, meta)?;
+                record.target = format!("{}:{}", meta.device.0, meta.device.1);
+            }
+        }
+        // Жесткая ссылка разделяет inode с целью, ее метаданные уже выставлены
+        if meta.kind != FileKind::Hardlink {
+            apply_metadata(&staged_path, meta)?;
+        }
+
+        // Повторная запись того же файла заменяет предыдущую
+        match self.staged.iter_mut().find(|(path, _)| *path == staged_path) {
+            Some(existing) => existing.1 = record,
+            None => self.staged.push((staged_path, record)),
+        }
+        Ok(())
+    }
+
+    // Добавление всего дерева директории (например, созданного скриптом установки)
+    pub fn stage_tree(&mut self, dir: &Path) -> io::Result<()> {
+        for entry in walkdir::WalkDir::new(dir).min_depth(1).into_iter().filter_map(|e| e.ok()) {
+            let rel = entry.path().strip_prefix(dir).map_err(io::Error::other)?.to_path_buf();
+            let Some(meta) = EntryMeta::from_path(entry.path())? else {
+                continue;
+            };
+            if meta.kind == FileKind::File {
+                let mut file = File::open(entry.path())?;
+                self.stage_entry(&rel, &meta, &mut file)?;
+            } else {
+                self.stage_entry(&rel, &meta, &mut io::empty())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Перемещение временных файлов на их места;
+    // новая версия измененного конфигурационного файла кладется рядом как .konpacnew
+    pub fn commit(mut self) -> io::Result<Vec<FileRecord>> {
+        for (staged_path, record) in &self.staged {
+            let destination = self.root.join(record.path.strip_prefix("/").unwrap_or(&record.path));
+            if self.is_locally_modified(&destination, record)? {
+                let new_path = konpacnew_path(&destination);
+                warn!("{:?} изменен локально, новая версия сохранена как {:?}", destination, new_path);
+                fs::rename(staged_path, new_path)?;
+                continue;
+            }
+            fs::rename(staged_path, destination)?;
+        }
+        self.committed = true;
+        let mut records: Vec<FileRecord> = self.dirs.drain(..).collect();
+        records.extend(self.staged.drain(..).map(|(_, record)| record));
+        Ok(records)
+    }
+}
+
+impl Drop for StagedInstall {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        for (staged_path, _) in &self.staged {
+            if let Err(e) = fs::remove_file(staged_path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    error!("Не удалось удалить временный файл {:?}: {}", staged_path, e);
+                }
+            }
+        }
+        for dir in self.created_dirs.iter().rev() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+// Рабочая директория для scripts и src, удаляется при выходе из области видимости
+pub struct WorkDir {
+    path: PathBuf,
+}
+
+impl WorkDir {
+    pub fn create(path: PathBuf) -> io::Result<Self> {
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir_all(&path)?;
+        Ok(WorkDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Отдельный временный каталог для теста; удаляется и тогда, когда проверка не прошла
+    #[cfg(test)]
+    pub fn temp(name: &str) -> Self {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let id = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        WorkDir::create(std::env::temp_dir().join(format!("konpac-{}-{}-{}", name, std::process::id(), id))).unwrap()
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            error!("Не удалось удалить рабочую директорию {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(kind: FileKind, target: Option<&str>) -> EntryMeta {
+        EntryMeta { kind, mode: 0o644, uid: 0, gid: 0, target: target.map(PathBuf::from), device: (0, 0) }
+    }
+
+    #[test]
+    fn hardlinks_point_only_to_staged_files() {
+        let work_dir = WorkDir::temp("stage-hardlinks");
+        let root = work_dir.path().join("root");
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/shadow"), "secret").unwrap();
+
+        let mut staged = StagedInstall::new(&root);
+        staged.stage_entry(Path::new("usr/bin/tool"), &meta(FileKind::File, None), &mut &b"binary"[..]).unwrap();
+        staged.stage_entry(Path::new("usr/bin/alias"), &meta(FileKind::Hardlink, Some("usr/bin/tool")), &mut io::empty()).unwrap();
+        // Уже установленный файл системы целью быть не может, как и директория пакета
+        let result = staged.stage_entry(Path::new("usr/bin/copy"), &meta(FileKind::Hardlink, Some("etc/shadow")), &mut io::empty());
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let result = staged.stage_entry(Path::new("usr/bin/dir"), &meta(FileKind::Hardlink, Some("usr/bin")), &mut io::empty());
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        staged.commit().unwrap();
+        let tool = fs::metadata(root.join("usr/bin/tool")).unwrap();
+        assert_eq!(fs::metadata(root.join("usr/bin/alias")).unwrap().ino(), tool.ino());
+        assert_eq!(fs::metadata(root.join("etc/shadow")).unwrap().nlink(), 1);
+        assert!(!root.join("usr/bin/copy").exists());
+    }
+
+    #[test]
+    fn parents_do_not_leave_root_through_symlinks() {
+        let work_dir = WorkDir::temp("stage-parents");
+        let root = work_dir.path().join("root");
+        let outside = work_dir.path().join("outside");
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        symlink("usr/lib", root.join("lib")).unwrap();
+        symlink(&outside, root.join("escape")).unwrap();
+        symlink("../../outside", root.join("usr/up")).unwrap();
+        symlink("missing", root.join("dangling")).unwrap();
+
+        let mut staged = StagedInstall::new(&root);
+        // Ссылка внутри root, как /lib -> usr/lib, допустима
+        staged.stage_entry(Path::new("lib/libfoo.so"), &meta(FileKind::File, None), &mut &b"lib"[..]).unwrap();
+        for rel in ["escape/file", "escape/new/file", "usr/up/file", "dangling/file"] {
+            let result = staged.stage_entry(Path::new(rel), &meta(FileKind::File, None), &mut &b"data"[..]);
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied, "{}", rel);
+        }
+        staged.commit().unwrap();
+
+        assert_eq!(fs::read(root.join("usr/lib/libfoo.so")).unwrap(), b"lib");
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        assert!(!root.join("missing").exists());
+    }
+}
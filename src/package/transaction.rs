@@ -0,0 +1,297 @@
+use std::collections::{HashMap, HashSet};
+use crate::error::KonpacError;
+use std::fs;
+use std::path::PathBuf;
+use std::pin::Pin;
+use futures::stream::{self, StreamExt};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{info, error};
+use crate::context::Event;
+use crate::db::{LocalDb, RepoPackage};
+use crate::package::cache::{cache_path, is_cached};
+use crate::package::depencies::PackageQuery;
+use crate::package::install::{install_package_from_file, read_manifest_from_archive, InstallOptions};
+use crate::repo::delta::fetch_package_delta;
+use crate::repo::download::progress_bar;
+use crate::repo::mirror::fetch_package;
+use crate::repo::utils::{candidate_repos, find_package_by_version, split_repo_prefix, Repository, SigLevel};
+
+// Что нужно найти в репозиториях: имя с возможным префиксом репозитория и ограничение версии
+struct Wanted {
+    query: PackageQuery,
+    // Запрошен пользователем, а не нужен как зависимость
+    requested: bool,
+}
+
+impl Wanted {
+    fn describe(&self) -> String {
+        format!("{}{}{}", self.query.name, self.query.comparison_operator, self.query.version)
+    }
+}
+
+// Пакет транзакции: запись индекса, репозиторий, из которого он берется, и путь загрузки
+pub struct PlannedPackage {
+    pub entry: RepoPackage,
+    pub repo: Repository,
+    pub file: PathBuf,
+    // Запрошен пользователем, а не нужен как зависимость
+    pub requested: bool,
+}
+
+// Пакеты в порядке установки: зависимости раньше зависящих от них
+pub struct Transaction {
+    pub packages: Vec<PlannedPackage>,
+}
+
+// Новейшая версия пакета в репозиториях по приоритету, без загрузки архива.
+// Без sync используются уже синхронизированные индексы
+pub async fn newest_available(name: &str, options: &InstallOptions, sync: bool) -> Result<PlannedPackage, KonpacError> {
+    resolve(&Wanted { query: PackageQuery::parse(name)?, requested: true }, options, sync).await
+}
+
+// Поиск пакета в репозиториях по приоритету
+async fn resolve(wanted: &Wanted, options: &InstallOptions, sync: bool) -> Result<PlannedPackage, KonpacError> {
+    let (repo_name, name) = split_repo_prefix(&wanted.query.name);
+    let repositories = candidate_repos(options.repos()?, repo_name, name);
+    if repositories.is_empty() {
+        return Err(KonpacError::NotFound(format!("Нет подходящих репозиториев для пакета {}", wanted.describe())));
+    }
+    // Из репозитория с наибольшим приоритетом берется новейшая подходящая версия
+    for repo in repositories {
+        match find_package_by_version(&options.fetcher, sync, name, &wanted.query.version, &wanted.query.comparison_operator, &repo).await {
+            Ok(Some(entry)) => {
+                info!("Найден пакет {}/{} {}", repo.name, entry.name, entry.version);
+                if repo.siglevel == SigLevel::Required {
+                    return Err(KonpacError::Signature(format!("Репозиторий {} требует подписанные пакеты, но проверка подписей не поддерживается", repo.name)));
+                }
+                let file = cache_path(&options.root, &entry);
+                return Ok(PlannedPackage { entry, repo, file, requested: wanted.requested });
+            }
+            Ok(None) => continue,
+            Err(e) => error!("Ошибка поиска в репозитории: {}", e),
+        }
+    }
+    Err(KonpacError::NotFound(format!("Пакет не найден ни в одном репозитории: {}", wanted.describe())))
+}
+
+// Одновременная загрузка архивов, которых нет в кэше, не больше parallel за раз
+async fn download_all(packages: &[PlannedPackage], options: &InstallOptions) -> Result<(), KonpacError> {
+    let packages: Vec<&PlannedPackage> = packages.iter()
+        .filter(|package| {
+            let cached = is_cached(&options.root, &package.entry);
+            if cached {
+                info!("Архив {} {} взят из кэша", package.entry.name, package.entry.version);
+            }
+            !cached
+        })
+        .collect();
+    if packages.is_empty() {
+        return Ok(());
+    }
+    for package in &packages {
+        if let Some(dir) = package.file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    let multi = MultiProgress::new();
+    let total = multi.add(ProgressBar::new(packages.len() as u64));
+    total.set_style(ProgressStyle::default_bar()
+        .template("{msg} [{bar:40.green/white}] {pos}/{len}")
+        .progress_chars("#>-"));
+    total.set_message("Всего");
+    let bars: Vec<ProgressBar> = packages.iter()
+        .map(|package| multi.add(progress_bar(&format!("{}-{}", package.entry.name, package.entry.version), package.entry.size)))
+        .collect();
+    // MultiProgress этой версии рисует только внутри join
+    let drawing = std::thread::spawn(move || multi.join_and_clear());
+
+    let failures: Vec<String> = stream::iter(packages.iter().zip(bars))
+        .map(|(package, pb)| {
+            let total = &total;
+            async move {
+                // Сначала сборка по разнице с версией из кэша, затем полная загрузка
+                let result = if fetch_package_delta(&options.fetcher, &package.repo, &package.entry, &package.file, &pb).await {
+                    Ok(())
+                } else {
+                    fetch_package(&options.fetcher, &package.repo, &package.entry, &package.file, &pb).await
+                };
+                pb.finish_and_clear();
+                total.inc(1);
+                if result.is_ok() {
+                    options.report(Event::Downloaded { name: package.entry.name.clone(), version: package.entry.version.clone() });
+                }
+                result.err().map(|e| format!("{}: {}", package.entry.name, e))
+            }
+        })
+        .buffer_unordered(options.parallel_downloads)
+        .filter_map(|failure| async move { failure })
+        .collect()
+        .await;
+    total.finish_and_clear();
+    let _ = drawing.join();
+
+    if !failures.is_empty() {
+        return Err(KonpacError::Network(format!("Не удалось загрузить пакеты: {}", failures.join("; "))));
+    }
+    Ok(())
+}
+
+// Порядок установки: каждый пакет после своих зависимостей
+pub fn install_order<T>(packages: Vec<T>, name: impl Fn(&T) -> &str, depens: &HashMap<String, Vec<String>>) -> Vec<T> {
+    fn visit(name: &str, depens: &HashMap<String, Vec<String>>, visited: &mut HashSet<String>, order: &mut Vec<String>) {
+        if !visited.insert(name.to_string()) {
+            return;
+        }
+        for depency in depens.get(name).into_iter().flatten() {
+            visit(depency, depens, visited, order);
+        }
+        order.push(name.to_string());
+    }
+
+    let mut visited = HashSet::new();
+    let mut order = Vec::new();
+    for package in &packages {
+        visit(name(package), depens, &mut visited, &mut order);
+    }
+    let mut packages = packages;
+    packages.sort_by_key(|package| order.iter().position(|ordered| ordered == name(package)));
+    packages
+}
+
+// Планирование: поиск пакетов и загрузка архивов волнами.
+// Зависимости известны только из манифеста внутри архива, поэтому каждая волна - зависимости предыдущей.
+// При просмотре (preview) индексы не синхронизируются и ничего не загружается:
+// зависимости пакетов, которых нет в кэше, остаются неизвестными
+async fn plan(db: &LocalDb, wanted: Vec<Wanted>, options: &InstallOptions, preview: bool) -> Result<Transaction, KonpacError> {
+    let mut packages = Vec::new();
+    let mut depens = HashMap::new();
+    let mut seen = HashSet::new();
+    let mut wave = wanted;
+    while !wave.is_empty() {
+        let mut batch = Vec::new();
+        let mut frozen = Vec::new();
+        for wanted in &wave {
+            let (_, name) = split_repo_prefix(&wanted.query.name);
+            if seen.contains(name) {
+                continue;
+            }
+            // Установленная версия, подходящая под ограничение, не меняется
+            if let Some(installed) = db.installed(name)? {
+                let reinstall = options.reinstall && wanted.requested;
+                if wanted.query.matches(&installed.version) && !reinstall {
+                    info!("Пакет уже установлен: {} {}", name, installed.version);
+                    continue;
+                }
+                if let Some(reason) = options.frozen_reason(db, name)? {
+                    frozen.push(format!("{} {} ({}) -> {}", name, installed.version, reason, wanted.describe()));
+                    continue;
+                }
+            }
+            seen.insert(name.to_string());
+            // Ненайденная зависимость - неудовлетворенная зависимость, а не ненайденный пакет
+            let package = match resolve(wanted, options, !preview).await {
+                Err(KonpacError::NotFound(e)) if !wanted.requested => return Err(KonpacError::DependencyUnsatisfied(e)),
+                result => result?,
+            };
+            batch.push(package);
+        }
+        // О закрепленных пакетах сообщается до загрузки, все сразу
+        if !frozen.is_empty() {
+            return Err(KonpacError::Conflict(format!("Транзакция изменила бы закрепленные пакеты: {}", frozen.join(", "))));
+        }
+
+        if !preview {
+            info!("Загрузка пакетов: Загрузка");
+            download_all(&batch, options).await?;
+            info!("Загрузка пакетов завершена");
+        }
+
+        wave = Vec::new();
+        for package in &batch {
+            if preview && !is_cached(&options.root, &package.entry) {
+                continue;
+            }
+            let manifest = read_manifest_from_archive(&package.file)?;
+            let mut names = Vec::new();
+            for depen in &manifest.depens {
+                let depency = PackageQuery::parse(depen)?;
+                names.push(split_repo_prefix(&depency.name).1.to_string());
+                wave.push(Wanted { query: depency, requested: false });
+            }
+            depens.insert(package.entry.name.clone(), names);
+        }
+        packages.extend(batch);
+    }
+    Ok(Transaction { packages: install_order(packages, |package| &package.entry.name, &depens) })
+}
+
+// Разбор запросов вида "name", "repo/name" или "name>=version"
+fn parse_wanted(queries: &[String], requested: bool) -> Result<Vec<Wanted>, KonpacError> {
+    queries.iter()
+        .map(|query| Ok(Wanted { query: PackageQuery::parse(query)?, requested }))
+        .collect()
+}
+
+// Транзакция для пакетов, запрошенных пользователем
+pub async fn plan_install(db: &LocalDb, queries: &[String], options: &InstallOptions) -> Result<Transaction, KonpacError> {
+    plan(db, parse_wanted(queries, true)?, options, false).await
+}
+
+// Транзакция для недостающих зависимостей
+pub async fn plan_depencies(db: &LocalDb, depens: &[String], options: &InstallOptions) -> Result<Transaction, KonpacError> {
+    plan(db, parse_wanted(depens, false)?, options, false).await
+}
+
+// Транзакция для просмотра по синхронизированным индексам и кэшу: ничего не загружается,
+// кэш и состояние синхронизации не меняются
+pub async fn plan_preview(db: &LocalDb, queries: &[String], requested: bool, options: &InstallOptions) -> Result<Transaction, KonpacError> {
+    plan(db, parse_wanted(queries, requested)?, options, true).await
+}
+
+// Установка загруженных пакетов по порядку.
+// Впервые установленная зависимость помечается, чтобы autoremove мог удалить ее, когда она станет не нужна
+pub async fn execute(db: &LocalDb, transaction: Transaction, options: &InstallOptions) -> Result<(), KonpacError> {
+    for package in transaction.packages {
+        info!("Установка {} {}: Установка", package.entry.name, package.entry.version);
+        let fresh = db.installed(&package.entry.name)?.is_none();
+        Pin::from(Box::new(install_package_from_file(db, &package.file, package.entry.sha256.as_deref(), options))).await?;
+        if fresh && !package.requested {
+            db.set_depency(&package.entry.name, true)?;
+        }
+        info!("Установка {} {} завершена, архив: {:?}", package.entry.name, package.entry.version, package.file);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn depens(edges: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        edges.iter()
+            .map(|(name, names)| (name.to_string(), names.iter().map(|name| name.to_string()).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn install_order_puts_depencies_first() {
+        let graph = depens(&[("app", &["lib", "tool"]), ("tool", &["lib", "base"]), ("lib", &["base"]), ("base", &[])]);
+        let order = install_order(vec!["app", "tool", "lib", "base"], |name| name, &graph);
+        assert_eq!(order, ["base", "lib", "tool", "app"]);
+
+        // Зависимости вне списка не добавляются, но порядок остальных соблюдается
+        let order = install_order(vec!["app", "tool"], |name| name, &graph);
+        assert_eq!(order, ["tool", "app"]);
+
+        // Независимые пакеты сохраняют исходный порядок
+        let order = install_order(vec!["b", "a", "c"], |name| name, &HashMap::new());
+        assert_eq!(order, ["b", "a", "c"]);
+    }
+
+    #[test]
+    fn install_order_survives_cycles() {
+        let graph = depens(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"])]);
+        let order = install_order(vec!["a", "b", "c"], |name| name, &graph);
+        assert_eq!(order, ["c", "b", "a"]);
+    }
+}
@@ -0,0 +1,46 @@
+use std::cmp::Ordering;
+use crate::error::KonpacError;
+use log::{info, warn};
+use crate::db::LocalDb;
+use crate::package::install::InstallOptions;
+use crate::package::transaction::{execute, newest_available, plan_install};
+use crate::package::utils::compare_versions;
+
+// Запросы на обновление установленных пакетов до новейших версий из репозиториев.
+// Закрепленные пакеты и пакеты из ignore_pkg пропускаются с предупреждением.
+// Без sync используются уже синхронизированные индексы
+pub async fn upgrade_queries(db: &LocalDb, options: &InstallOptions, sync: bool) -> Result<Vec<String>, KonpacError> {
+    info!("Поиск обновлений: Поиск");
+    let mut queries = Vec::new();
+    for installed in db.packages()? {
+        let newest = match newest_available(&installed.name, options, sync).await {
+            Ok(newest) => newest,
+            Err(e) => {
+                warn!("Пакет {} пропущен: {}", installed.name, e);
+                continue;
+            }
+        };
+        if compare_versions(&newest.entry.version, &installed.version) != Ordering::Greater {
+            continue;
+        }
+        if let Some(reason) = options.frozen_reason(db, &installed.name)? {
+            warn!("Пакет {} закреплен ({}), обновление {} -> {} пропущено", installed.name, reason, installed.version, newest.entry.version);
+            continue;
+        }
+        info!("Обновление {} {} -> {}", installed.name, installed.version, newest.entry.version);
+        queries.push(format!("{}={}", installed.name, newest.entry.version));
+    }
+    info!("Поиск обновлений завершен");
+    Ok(queries)
+}
+
+// Обновление всех установленных пакетов до новейших версий из репозиториев
+pub async fn upgrade_system(db: &LocalDb, options: &InstallOptions) -> Result<(), KonpacError> {
+    let queries = upgrade_queries(db, options, true).await?;
+    if queries.is_empty() {
+        info!("Система обновлена");
+        return Ok(());
+    }
+    let transaction = plan_install(db, &queries, options).await?;
+    execute(db, transaction, options).await
+}
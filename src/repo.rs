@@ -1,2 +1,8 @@
+pub mod delta;
+pub mod download;
 pub mod gen;
+pub mod index;
+pub mod mirror;
+pub mod serve;
+pub mod transport;
 pub mod utils;
\ No newline at end of file
@@ -0,0 +1,129 @@
+use crate::error::KonpacError;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use indicatif::ProgressBar;
+use log::{info, warn};
+use crate::package::backup::file_sha256;
+use crate::package::cache::cache_path;
+use crate::repo::download::part_path;
+use crate::repo::mirror::{fetch_repo_file, Fetcher};
+use crate::db::{DeltaEntry, RepoPackage, SyncDb};
+use crate::repo::index::index_path;
+use crate::repo::utils::Repository;
+
+// Расширение файлов разниц между версиями пакета
+pub const DELTA_EXTENSION: &str = "kdelta";
+// Уровень сжатия разниц: они создаются один раз на сервере
+const DELTA_LEVEL: i32 = 19;
+
+// Окно сжатия должно охватывать старый и новый архив целиком
+fn window_log(size: u64) -> u32 {
+    (u64::BITS - size.max(1).leading_zeros()).clamp(10, 30)
+}
+
+// Разница между архивами: новый архив, сжатый zstd со старым в качестве префикса (как zstd --patch-from)
+pub fn make_delta(old: &Path, new: &Path, delta: &Path) -> Result<(), KonpacError> {
+    let base = fs::read(old)?;
+    let new_size = fs::metadata(new)?.len();
+    let mut encoder = zstd::stream::write::Encoder::with_ref_prefix(File::create(delta)?, DELTA_LEVEL, &base)?;
+    encoder.window_log(window_log(base.len() as u64 + new_size))?;
+    encoder.long_distance_matching(true)?;
+    io::copy(&mut File::open(new)?, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+// Сборка нового архива из старого и разницы
+pub fn apply_delta(base: &Path, delta: &Path, output: &Path) -> Result<(), KonpacError> {
+    let base = fs::read(base)?;
+    let mut decoder = zstd::stream::read::Decoder::with_ref_prefix(BufReader::new(File::open(delta)?), &base)?;
+    decoder.window_log_max(31)?;
+    io::copy(&mut decoder, &mut File::create(output)?)?;
+    Ok(())
+}
+
+// Архив из кэша, к которому применяется разница, если он там есть и не поврежден
+fn cached_base(root: &Path, package: &RepoPackage, delta: &DeltaEntry) -> Option<PathBuf> {
+    let base = cache_path(root, &RepoPackage {
+        version: delta.from_version.clone(),
+        sha256: Some(delta.from_sha256.clone()),
+        ..package.clone()
+    });
+    file_sha256(&base).ok().filter(|sha256| *sha256 == delta.from_sha256).map(|_| base)
+}
+
+// Сборка архива по разнице и проверка его по sha256 полного пакета
+async fn fetch_delta(fetcher: &Fetcher, repo: &Repository, package: &RepoPackage, delta: &DeltaEntry, base: &Path, file_name: &Path, pb: &ProgressBar) -> Result<(), KonpacError> {
+    let delta_path = file_name.with_extension(DELTA_EXTENSION);
+    fetch_repo_file(fetcher, repo, &delta.url, &delta.expected(), &delta_path, pb).await?;
+    let part = part_path(file_name);
+    let applied = apply_delta(base, &delta_path, &part);
+    let _ = fs::remove_file(&delta_path);
+    applied?;
+
+    let size = fs::metadata(&part)?.len();
+    let sha256 = file_sha256(&part)?;
+    if package.size.is_some_and(|expected| expected != size) || package.sha256.as_ref() != Some(&sha256) {
+        fs::remove_file(&part)?;
+        return Err(KonpacError::Checksum(format!("Собранный архив не совпадает с индексом: {}", sha256)));
+    }
+    fs::rename(&part, file_name)?;
+    Ok(())
+}
+
+// Загрузка архива через разницу с версией из кэша.
+// false - подходящей разницы или исходного архива нет, либо сборка не удалась и нужна полная загрузка
+pub async fn fetch_package_delta(fetcher: &Fetcher, repo: &Repository, package: &RepoPackage, file_name: &Path, pb: &ProgressBar) -> bool {
+    if package.sha256.is_none() {
+        return false;
+    }
+    let deltas = match SyncDb::open(&index_path(fetcher.root(), repo)).and_then(|db| db.deltas(package)) {
+        Ok(deltas) => deltas,
+        Err(e) => {
+            warn!("Не удалось прочитать разницы {}: {}", package.name, e);
+            return false;
+        }
+    };
+    // Разницы отсортированы по размеру, берется самая маленькая с исходным архивом в кэше
+    let Some((delta, base)) = deltas.iter().find_map(|delta| cached_base(fetcher.root(), package, delta).map(|base| (delta, base))) else {
+        return false;
+    };
+    info!("Сборка {} {} из {} по разнице ({} байт)", package.name, package.version, delta.from_version, delta.size);
+    pb.set_length(delta.size);
+    match fetch_delta(fetcher, repo, package, delta, &base, file_name, pb).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Разница {} {} -> {} не применена, полная загрузка: {}", package.name, delta.from_version, package.version, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::stage::WorkDir;
+
+    #[test]
+    fn delta_rebuilds_new_archive_from_old() {
+        let work_dir = WorkDir::temp("delta-test");
+        let dir = work_dir.path();
+        let old: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut new = old.clone();
+        new[1000..1100].fill(7);
+        new.extend_from_slice(b"new version tail");
+        fs::write(dir.join("old.kpkg"), &old).unwrap();
+        fs::write(dir.join("new.kpkg"), &new).unwrap();
+
+        make_delta(&dir.join("old.kpkg"), &dir.join("new.kpkg"), &dir.join("new.kdelta")).unwrap();
+        assert!(fs::metadata(dir.join("new.kdelta")).unwrap().len() < new.len() as u64 / 10);
+        apply_delta(&dir.join("old.kpkg"), &dir.join("new.kdelta"), &dir.join("rebuilt.kpkg")).unwrap();
+        assert_eq!(fs::read(dir.join("rebuilt.kpkg")).unwrap(), new);
+
+        // С другим исходным архивом результат не совпадает с новым архивом
+        fs::write(dir.join("other.kpkg"), vec![0u8; old.len()]).unwrap();
+        let rebuilt = apply_delta(&dir.join("other.kpkg"), &dir.join("new.kdelta"), &dir.join("wrong.kpkg"));
+        assert!(rebuilt.is_err() || fs::read(dir.join("wrong.kpkg")).unwrap() != new);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::error::KonpacError;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::info;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Certificate, Client, Identity, NoProxy, Proxy, RequestBuilder};
+use url::Url;
+use crate::config::Config;
+use crate::package::backup::file_sha256;
+use crate::repo::transport::Transports;
+
+// Время на установку соединения
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Ожидаемые размер и sha256 файла из индекса репозитория; max_size ограничивает файлы, размер которых заранее неизвестен
+#[derive(Debug, Clone, Default)]
+pub struct Expected {
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+    pub max_size: Option<u64>,
+}
+
+// Авторизация в репозитории; учетные данные берутся из файла, доступного только root
+#[derive(Clone)]
+pub enum Auth {
+    Basic { username: String, password: Option<String> },
+    Bearer(String),
+}
+
+impl Auth {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Auth::Basic { .. } => "basic",
+            Auth::Bearer(_) => "bearer",
+        }
+    }
+}
+
+// Настройки HTTP репозитория
+#[derive(Clone, Default)]
+pub struct HttpOptions {
+    pub auth: Option<Auth>,
+    // Дополнительные заголовки каждого запроса
+    pub headers: Vec<(String, String)>,
+    // Дополнительные корневые сертификаты (PEM)
+    pub ca_cert: Option<PathBuf>,
+    // Клиентский сертификат и ключ (PEM); без ключа он ищется в файле сертификата
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+// Клиент HTTP с настройками репозитория и общими настройками прокси
+pub struct HttpClient {
+    client: Client,
+    auth: Option<Auth>,
+}
+
+impl HttpClient {
+    pub fn new(http: &HttpOptions, config: &Config) -> Result<Self, KonpacError> {
+        let mut builder = Client::builder().connect_timeout(CONNECT_TIMEOUT);
+
+        // Без proxy в konpac.conf используются переменные окружения http_proxy и https_proxy
+        if let Some(proxy) = &config.proxy {
+            let no_proxy = config.no_proxy.as_deref().and_then(NoProxy::from_string);
+            builder = builder.proxy(Proxy::all(proxy)?.no_proxy(no_proxy));
+        }
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &http.headers {
+            let mut value = HeaderValue::from_str(value)?;
+            value.set_sensitive(true);
+            headers.insert(HeaderName::from_bytes(name.as_bytes())?, value);
+        }
+        builder = builder.default_headers(headers);
+
+        if let Some(ca_cert) = &http.ca_cert {
+            let pem = fs::read(ca_cert).map_err(|e| KonpacError::Invalid(format!("Не удалось прочитать ca_cert {:?}: {}", ca_cert, e)))?;
+            for certificate in Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if let Some(client_cert) = &http.client_cert {
+            let client_key = http.client_key.as_ref().unwrap_or(client_cert);
+            let certificate = fs::read(client_cert).map_err(|e| KonpacError::Invalid(format!("Не удалось прочитать client_cert {:?}: {}", client_cert, e)))?;
+            let key = fs::read(client_key).map_err(|e| KonpacError::Invalid(format!("Не удалось прочитать client_key {:?}: {}", client_key, e)))?;
+            builder = builder.identity(Identity::from_pkcs8_pem(&certificate, &key)?);
+        }
+
+        Ok(HttpClient { client: builder.build()?, auth: http.auth.clone() })
+    }
+
+    pub(crate) fn get(&self, url: &str) -> RequestBuilder {
+        let request = self.client.get(url);
+        match &self.auth {
+            Some(Auth::Basic { username, password }) => request.basic_auth(username, password.as_ref()),
+            Some(Auth::Bearer(token)) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+// Недокачанный файл лежит рядом с итоговым
+pub fn part_path(file_name: &Path) -> PathBuf {
+    let mut name = file_name.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+// Полоса прогресса одной загрузки: с размером, если он известен, иначе счетчик байт
+pub fn progress_bar(name: &str, total: Option<u64>) -> ProgressBar {
+    let pb = match total {
+        Some(total) => {
+            let pb = ProgressBar::new(total);
+            pb.set_style(ProgressStyle::default_bar()
+                .template("{msg} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+                .progress_chars("#>-"));
+            pb
+        }
+        None => {
+            let pb = ProgressBar::new_spinner();
+            pb.set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg} {bytes}"));
+            pb
+        }
+    };
+    pb.set_message(name.to_string());
+    pb
+}
+
+// Загрузка файла транспортом схемы URL: данные пишутся в .part, итоговый файл появляется только после проверки.
+// Полосу прогресса завершает вызывающий: при повторах она используется заново
+pub async fn download(transports: &Transports, url: &str, file_name: &Path, expected: &Expected, pb: &ProgressBar) -> Result<(), KonpacError> {
+    info!("Fetching URL: {}", url);
+    let parsed_url = Url::parse(url)?;
+    let part = part_path(file_name);
+    transports.fetch(&parsed_url, &part, expected, pb).await?;
+
+    let size = fs::metadata(&part)?.len();
+    if let Some(expected_size) = expected.size {
+        if size != expected_size {
+            fs::remove_file(&part)?;
+            return Err(KonpacError::Checksum(format!("Размер {} не совпадает с индексом: {} != {}", url, size, expected_size)));
+        }
+    }
+    if let Some(max_size) = expected.max_size {
+        if size > max_size {
+            fs::remove_file(&part)?;
+            return Err(KonpacError::Invalid(format!("Размер {} больше допустимого: {} > {}", url, size, max_size)));
+        }
+    }
+    if let Some(expected_sha) = &expected.sha256 {
+        let sha = file_sha256(&part)?;
+        if &sha != expected_sha {
+            fs::remove_file(&part)?;
+            return Err(KonpacError::Checksum(format!("Контрольная сумма {} не совпадает: {} != {}", url, sha, expected_sha)));
+        }
+    }
+    fs::rename(&part, file_name)?;
+    Ok(())
+}
@@ -1,68 +1,408 @@
-
-use std::{fs::{self, File}, path::{Path,PathBuf}};
+use std::{collections::{BTreeMap, HashSet}, fs, path::{Path, PathBuf}};
+use log::{info, warn};
+use url::Url;
 use walkdir::WalkDir;
-use rusqlite::{params, Connection, Result};
-use crate::repo::utils::DbPackageEntry;
-
-
-fn write_repo_db(package: DbPackageEntry,db_path: &Path) -> Result<()> {
-    let conn = Connection::open(db_path)?;
-
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS packages (
-            name TEXT NOT NULL,
-            version TEXT NOT NULL,
-            path TEXT NOT NULL,
-            PRIMARY KEY (name, version)
-        )", 
-        [],
-    )?;
-
-    conn.execute(
-        "INSERT OR REPLACE INTO packages (name, version, path)
-         VALUES (?1, ?2, ?3)",
-        params![
-            package.name,
-            package.version,
-            package.url,
-        ],
-    )?;
+use crate::error::KonpacError;
+use crate::db::{DeltaEntry, RepoDb, RepoPackage, SyncDb};
+use crate::package::install::read_manifest_from_archive;
+use crate::package::archive::is_package_file;
+use crate::package::backup::file_sha256;
+use crate::package::preview::{DryRun, PackageChange};
+use crate::package::utils::compare_versions;
+use crate::repo::delta::{make_delta, DELTA_EXTENSION};
+use crate::repo::index::{publish_index, published_stamp};
+
+// Итог изменения индекса; при любой ошибке konpac завершается с ненулевым кодом
+#[derive(Default)]
+pub struct IndexSummary {
+    pub added: usize,
+    pub removed: usize,
+    pub deleted: usize,
+    pub freed: u64,
+    pub deltas: usize,
+    pub failures: Vec<String>,
+}
+
+impl IndexSummary {
+    fn fail(&mut self, what: impl std::fmt::Display, e: impl std::fmt::Display) {
+        self.failures.push(format!("{}: {}", what, e));
+    }
+}
+
+// Адрес архива относительно каталога индекса, чтобы репозиторий можно было переносить
+// и раздавать с любого зеркала. Архив вне каталога индекса записывается абсолютным file:// адресом
+fn package_url(index_dir: &Path, path: &Path) -> Result<String, KonpacError> {
+    let index_dir = fs::canonicalize(index_dir)?;
+    let absolute_path = fs::canonicalize(path)?;
+    let file_url = Url::from_file_path(&absolute_path)
+        .map_err(|_| KonpacError::Invalid(format!("Некорректный путь {:?}", absolute_path)))?;
+    if !absolute_path.starts_with(&index_dir) {
+        return Ok(file_url.to_string());
+    }
+    let base = Url::from_directory_path(&index_dir)
+        .map_err(|_| KonpacError::Invalid(format!("Некорректный путь {:?}", index_dir)))?;
+    Ok(base.make_relative(&file_url).unwrap_or_else(|| file_url.to_string()))
+}
+
+// Запись индекса для архива: имя, версия и архитектура берутся из манифеста внутри него
+fn package_entry(index_dir: &Path, path: &Path) -> Result<RepoPackage, KonpacError> {
+    let manifest = read_manifest_from_archive(path)?;
+    Ok(RepoPackage {
+        name: manifest.name,
+        version: manifest.version,
+        url: package_url(index_dir, path)?,
+        arch: manifest.arch,
+        // Размер и sha256 проверяются клиентом после загрузки
+        size: Some(fs::metadata(path)?.len()),
+        sha256: Some(file_sha256(path)?),
+    })
+}
+
+// Добавление архивов в индекс; запись с тем же именем, версией и архитектурой заменяется
+fn add_files(db: &RepoDb, index_dir: &Path, files: &[PathBuf], summary: &mut IndexSummary) -> BTreeMap<String, usize> {
+    // Количество пакетов по архитектурам
+    let mut arches = BTreeMap::new();
+    for file in files {
+        let result = package_entry(index_dir, file).and_then(|package| {
+            db.add_package(&package)?;
+            Ok(package)
+        });
+        match result {
+            Ok(package) => {
+                info!("Добавлен {} {} ({})", package.name, package.version, package.arch);
+                *arches.entry(package.arch).or_default() += 1;
+                summary.added += 1;
+            }
+            Err(e) => summary.fail(file.display(), e),
+        }
+    }
+    arches
+}
+
+// Новая отметка и публикация индекса после изменения на месте
+fn republish(db: RepoDb, db_path: &Path, summary: &IndexSummary) -> Result<(), KonpacError> {
+    if summary.added + summary.removed == 0 {
+        return Ok(());
+    }
+    db.stamp(published_stamp(index_dir(db_path)))?;
+    db.close()?;
+    publish_index(db_path)?;
+    Ok(())
+}
+
+// Версии пакетов в индексе по имени и архитектуре, от старых к новым
+type IndexedVersions = BTreeMap<(String, String), Vec<RepoPackage>>;
+
+fn indexed_versions(packages: Vec<RepoPackage>) -> IndexedVersions {
+    let mut versions = IndexedVersions::new();
+    for package in packages {
+        versions.entry((package.name.clone(), package.arch.clone())).or_default().push(package);
+    }
+    for versions in versions.values_mut() {
+        versions.sort_by(|a, b| compare_versions(&a.version, &b.version));
+    }
+    versions
+}
+
+// Разницы, у которых в индексе нет исходной или итоговой версии
+fn orphaned_deltas(packages: &[RepoPackage], deltas: Vec<DeltaEntry>) -> Vec<DeltaEntry> {
+    let versions: HashSet<(&str, &str, &str)> = packages.iter()
+        .map(|package| (package.name.as_str(), package.arch.as_str(), package.version.as_str()))
+        .collect();
+    deltas.into_iter()
+        .filter(|delta| {
+            !versions.contains(&(delta.name.as_str(), delta.arch.as_str(), delta.from_version.as_str()))
+                || !versions.contains(&(delta.name.as_str(), delta.arch.as_str(), delta.to_version.as_str()))
+        })
+        .collect()
+}
+
+fn short_sha(sha256: &str) -> &str {
+    sha256.get(..12).unwrap_or(sha256)
+}
+
+// Разница между соседними версиями пакета в каталоге deltas.
+// В имени файла есть начало sha256 обоих архивов, поэтому готовая разница пересобранных пакетов не переиспользуется
+fn write_delta(db: &RepoDb, db_path: &Path, old: &RepoPackage, new: &RepoPackage, old_sha: &str, new_sha: &str) -> Result<Option<PathBuf>, KonpacError> {
+    let not_local = |url: &str| KonpacError::NotFound(format!("Архив {} не локальный", url));
+    let old_path = archive_path(db_path, &old.url).ok_or_else(|| not_local(&old.url))?;
+    let new_path = archive_path(db_path, &new.url).ok_or_else(|| not_local(&new.url))?;
+    let dir = index_dir(db_path).join("deltas");
+    fs::create_dir_all(&dir)?;
+    let delta_path = dir.join(format!(
+        "{}-{}-{}-{}-{}.{}", new.name, old.version, new.version, short_sha(old_sha), short_sha(new_sha), DELTA_EXTENSION
+    ));
+    if !delta_path.exists() {
+        let tmp = delta_path.with_extension("tmp");
+        make_delta(&old_path, &new_path, &tmp)?;
+        fs::rename(&tmp, &delta_path)?;
+    }
+    // Разница не меньше полного архива бесполезна
+    let size = fs::metadata(&delta_path)?.len();
+    if size >= fs::metadata(&new_path)?.len() {
+        fs::remove_file(&delta_path)?;
+        return Ok(None);
+    }
+    db.add_delta(&DeltaEntry {
+        name: new.name.clone(),
+        arch: new.arch.clone(),
+        from_version: old.version.clone(),
+        from_sha256: old_sha.to_string(),
+        to_version: new.version.clone(),
+        url: package_url(index_dir(db_path), &delta_path)?,
+        size,
+        sha256: file_sha256(&delta_path)?,
+    })?;
+    Ok(Some(delta_path))
+}
 
+// Разницы между всеми соседними версиями; разницы, которые больше не нужны, удаляются
+fn generate_deltas(db: &RepoDb, db_path: &Path, summary: &mut IndexSummary) -> Result<(), KonpacError> {
+    let mut written = Vec::new();
+    for ((name, arch), versions) in indexed_versions(db.packages()?) {
+        for pair in versions.windows(2) {
+            let [old, new] = pair else { continue };
+            let (Some(old_sha), Some(new_sha)) = (&old.sha256, &new.sha256) else { continue };
+            match write_delta(db, db_path, old, new, old_sha, new_sha) {
+                Ok(Some(delta_path)) => {
+                    info!("Разница {} {} -> {} ({})", name, old.version, new.version, arch);
+                    summary.deltas += 1;
+                    written.push(delta_path);
+                }
+                Ok(None) => info!("Разница {} {} -> {} не меньше архива и пропущена", name, old.version, new.version),
+                Err(e) => summary.fail(format!("разница {} {} -> {}", name, old.version, new.version), e),
+            }
+        }
+    }
+    let dir = index_dir(db_path).join("deltas");
+    if dir.exists() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if !written.contains(&path) {
+                fs::remove_file(&path)?;
+            }
+        }
+    }
     Ok(())
 }
 
-pub fn generate_repo(path: PathBuf) {
+// Удаление разниц, у которых исчезла исходная или итоговая версия
+fn prune_deltas(db: &RepoDb, db_path: &Path, summary: &mut IndexSummary) -> Result<(), KonpacError> {
+    for delta in orphaned_deltas(&db.packages()?, db.deltas()?) {
+        if let Some(path) = prunable_path(db_path, &delta.url) {
+            if let Ok(metadata) = fs::metadata(&path) {
+                match fs::remove_file(&path) {
+                    Ok(()) => {
+                        summary.deleted += 1;
+                        summary.freed += metadata.len();
+                    }
+                    Err(e) => summary.fail(path.display(), e),
+                }
+            }
+        }
+        db.remove_delta(&delta)?;
+    }
+    Ok(())
+}
+
+// Полная пересборка индекса каталога; новый индекс подменяет старый целиком,
+// чтобы клиенты не получили его наполовину записанным
+pub fn generate_repo(path: &Path, deltas: bool) -> Result<IndexSummary, KonpacError> {
     let db_path = path.join("packages.db");
-    let _file = File::create(&db_path);
-    for entry in WalkDir::new(path)
+    let tmp_path = path.join("packages.db.tmp");
+    if tmp_path.exists() {
+        fs::remove_file(&tmp_path)?;
+    }
+    let files: Vec<PathBuf> = WalkDir::new(path)
         .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-        .filter(|e| e.path().extension().map_or(false, |ext| ext == "kpkg")) {
-            let version = entry.file_name() // Получаем имя файла
-                .to_str() // Преобразуем в &str
-                .unwrap_or("") // Если преобразование не удалось, используем пустую строку
-                .rsplitn(2, '-') // Разделяем строку по последнему тире (максимум 2 части)
-                .next() // Берем часть после последнего тире
-                .unwrap_or("") // Если разделение не удалось, используем пустую строку
-                .rsplitn(2, '.') // Разделяем по последней точке (максимум 2 части)
-                .nth(1) // Берем часть до последней точки
-                .unwrap_or(""); 
-                 
-            let prefix = entry.file_name() // Получаем имя файла
-                .to_str() // Преобразуем в &str
-                .unwrap_or("") // Если преобразование не удалось, используем пустую строку
-                .split('-') // Разделяем строку по тире
-                .next() // Берем первую часть (до первого тире)
-                .unwrap_or(""); // Если разделение не удалось, используем пустую строку
-            let absolute_path = fs::canonicalize(entry.path()).unwrap();
-            println!("{:?}", absolute_path);
-            let package: DbPackageEntry = DbPackageEntry{
-                name: prefix.to_string(),
-                version: version.to_string(),
-                url: format!("file://{}",&absolute_path.to_str().unwrap())
-            };
-            
-            let _ = write_repo_db(package, &db_path);
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file() && is_package_file(entry.path()))
+        .map(|entry| entry.into_path())
+        .collect();
+
+    let mut summary = IndexSummary::default();
+    let db = RepoDb::open(&tmp_path)?;
+    let arches = add_files(&db, path, &files, &mut summary);
+    if deltas {
+        generate_deltas(&db, &tmp_path, &mut summary)?;
+    }
+    db.stamp(published_stamp(path))?;
+    db.close()?;
+    fs::rename(&tmp_path, &db_path)?;
+    publish_index(&db_path)?;
+    for (arch, count) in arches {
+        info!("{}: {} пакетов", arch, count);
+    }
+    Ok(summary)
+}
+
+// Добавление архивов в существующий индекс без обхода всего каталога
+pub fn add_packages(db_path: &Path, files: &[PathBuf]) -> Result<IndexSummary, KonpacError> {
+    let mut summary = IndexSummary::default();
+    let db = RepoDb::open(db_path)?;
+    add_files(&db, index_dir(db_path), files, &mut summary);
+    republish(db, db_path, &summary)?;
+    Ok(summary)
+}
+
+// Удаление всех версий пакета из индекса; архивы остаются на месте
+pub fn remove_package(db_path: &Path, name: &str) -> Result<IndexSummary, KonpacError> {
+    let mut summary = IndexSummary::default();
+    let db = RepoDb::open(db_path)?;
+    summary.removed = db.remove_package(name)?;
+    if summary.removed == 0 {
+        summary.fail(name, "пакета нет в индексе");
+    }
+    republish(db, db_path, &summary)?;
+    Ok(summary)
+}
+
+// Каталог индекса: относительные адреса архивов отсчитываются от него
+fn index_dir(db_path: &Path) -> &Path {
+    match db_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+// Путь архива на диске; у удаленных архивов его нет
+fn archive_path(db_path: &Path, url: &str) -> Option<PathBuf> {
+    let base = Url::from_directory_path(fs::canonicalize(index_dir(db_path)).ok()?).ok()?;
+    base.join(url).ok()
+        .filter(|url| url.scheme() == "file")
+        .and_then(|url| url.to_file_path().ok())
+}
+
+// Путь архива или разницы, который prune может удалить: только внутри каталога индекса, в том числе
+// после разрешения символических ссылок. Адрес в индексе может указывать и на файл вне репозитория
+// (абсолютный file:// адрес из package_url), такие файлы не удаляются
+fn prunable_path(db_path: &Path, url: &str) -> Option<PathBuf> {
+    let path = archive_path(db_path, url)?;
+    let dir = fs::canonicalize(index_dir(db_path)).ok()?;
+    // Отсутствующий файл удалять не придется, достаточно проверить сам адрес
+    let inside = match fs::canonicalize(&path) {
+        Ok(canonical) => canonical.starts_with(&dir),
+        Err(_) => path.starts_with(&dir),
+    };
+    inside.then_some(path)
+}
+
+// Удаление устаревших версий: для каждого пакета и архитектуры остаются keep новейших,
+// остальные архивы удаляются вместе со строками индекса
+pub fn prune_repo(db_path: &Path, keep: usize) -> Result<IndexSummary, KonpacError> {
+    let mut summary = IndexSummary::default();
+    let db = RepoDb::open(db_path)?;
+    for ((name, arch), versions) in indexed_versions(db.packages()?) {
+        // От новых версий к старым
+        for old in versions.into_iter().rev().skip(keep) {
+            let package = format!("{} {} ({})", name, old.version, arch);
+            // Строка удаляется только после архива, чтобы сбой не оставил архив без записи в индексе
+            match prunable_path(db_path, &old.url) {
+                Some(path) => match fs::metadata(&path) {
+                    Ok(metadata) => match fs::remove_file(&path) {
+                        Ok(()) => {
+                            summary.deleted += 1;
+                            summary.freed += metadata.len();
+                        }
+                        Err(e) => {
+                            summary.fail(&package, e);
+                            continue;
+                        }
+                    },
+                    Err(_) => warn!("Архив {} уже отсутствует: {:?}", package, path),
+                },
+                None => warn!("Архив {} не в каталоге репозитория и не удаляется: {}", package, old.url),
+            }
+            match db.remove_version(&old) {
+                Ok(_) => {
+                    info!("Удален {}", package);
+                    summary.removed += 1;
+                }
+                Err(e) => summary.fail(&package, e),
+            }
+        }
+    }
+    prune_deltas(&db, db_path, &mut summary)?;
+    republish(db, db_path, &summary)?;
+    Ok(summary)
+}
+
+// Что удалил бы prune_repo: старые версии, их архивы и разницы, у которых не останется одной из версий.
+// Индекс открывается только на чтение и не меняется
+pub fn preview_prune(db_path: &Path, keep: usize) -> Result<DryRun, KonpacError> {
+    let mut dry_run = DryRun::default();
+    let db = SyncDb::open(db_path)?;
+    let mut kept = Vec::new();
+    for ((name, arch), versions) in indexed_versions(db.all_packages()?) {
+        let pruned = versions.len().saturating_sub(keep);
+        for (i, old) in versions.into_iter().enumerate() {
+            if i >= pruned {
+                kept.push(old);
+                continue;
+            }
+            if let Some(path) = prunable_path(db_path, &old.url) {
+                if let Ok(metadata) = fs::metadata(&path) {
+                    dry_run.remove_size += metadata.len();
+                    dry_run.delete.push(path);
+                }
+            }
+            dry_run.packages.push(PackageChange {
+                name: format!("{} ({})", name, arch),
+                action: "remove",
+                old_version: Some(old.version),
+                new_version: None,
+                source: None,
+                download: None,
+            });
         }
-}
\ No newline at end of file
+    }
+
+    for delta in orphaned_deltas(&kept, db.all_deltas()?) {
+        let Some(path) = prunable_path(db_path, &delta.url) else { continue };
+        if let Ok(metadata) = fs::metadata(&path) {
+            dry_run.remove_size += metadata.len();
+            dry_run.delete.push(path);
+        }
+    }
+    Ok(dry_run)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::archive::write_archive;
+    use crate::package::stage::WorkDir;
+
+    fn write_package(path: &Path, version: &str) {
+        write_archive(path, &[("package.yml", &format!("name: pkg\nversion: \"{}\"\n", version))]);
+    }
+
+    #[test]
+    fn prune_keeps_files_outside_repository() {
+        let work_dir = WorkDir::temp("gen-prune");
+        let repo = work_dir.path().join("repo");
+        let outside = work_dir.path().join("outside");
+        fs::create_dir_all(&repo).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        write_package(&repo.join("pkg-3.0.kpkg"), "3.0");
+        write_package(&repo.join("pkg-2.0.kpkg"), "2.0");
+        // Архив вне репозитория и ссылка на него внутри записываются абсолютными file:// адресами
+        write_package(&outside.join("pkg-1.0.kpkg"), "1.0");
+        write_package(&outside.join("pkg-0.5.kpkg"), "0.5");
+        std::os::unix::fs::symlink(outside.join("pkg-0.5.kpkg"), repo.join("pkg-0.5.kpkg")).unwrap();
+        generate_repo(&repo, false).unwrap();
+        let db_path = repo.join("packages.db");
+        add_packages(&db_path, &[outside.join("pkg-1.0.kpkg"), repo.join("pkg-0.5.kpkg")]).unwrap();
+
+        let preview = preview_prune(&db_path, 1).unwrap();
+        assert_eq!(preview.packages.len(), 3);
+        assert_eq!(preview.delete, [fs::canonicalize(&repo).unwrap().join("pkg-2.0.kpkg")]);
+
+        let summary = prune_repo(&db_path, 1).unwrap();
+        assert_eq!((summary.removed, summary.deleted), (3, 1));
+        assert!(!repo.join("pkg-2.0.kpkg").exists());
+        assert!(repo.join("pkg-3.0.kpkg").exists());
+        assert!(outside.join("pkg-1.0.kpkg").exists());
+        assert!(outside.join("pkg-0.5.kpkg").exists());
+    }
+}
@@ -0,0 +1,251 @@
+use crate::error::KonpacError;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use indicatif::ProgressBar;
+use ini::Ini;
+use log::{info, warn};
+use crate::consts::paths::{rooted, SYNC_PATH};
+use crate::db::SyncDb;
+use crate::package::backup::file_sha256;
+use crate::repo::download::{download, progress_bar, Expected};
+use crate::repo::transport::Transports;
+use crate::repo::mirror::{fetch_from_mirrors, join_url, Fetcher};
+use crate::repo::utils::Repository;
+
+// Версия формата индекса; клиент отказывается от индексов новее, чем умеет читать
+pub const INDEX_FORMAT: u32 = 2;
+// Файлы, которые публикует konpac --gen-repo рядом с packages.db
+pub const INDEX_FILE: &str = "packages.db.zst";
+pub const MANIFEST_FILE: &str = "packages.manifest";
+// Манифест - несколько строк, поэтому зеркалу не дают прислать больше
+const MAX_MANIFEST_SIZE: u64 = 64 * 1024;
+// Несжатый индекс старых репозиториев без манифеста
+pub const LEGACY_INDEX_FILE: &str = "packages.db";
+
+// Манифест индекса: проверяется первым, чтобы не загружать неизменившийся индекс
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexManifest {
+    pub format: u32,
+    // Время генерации индекса в секундах, растет с каждой публикацией
+    pub generated: u64,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl IndexManifest {
+    pub fn parse(content: &str) -> Result<Self, KonpacError> {
+        let ini = Ini::load_from_str(content)?;
+        let section = ini.section(Some("index")).ok_or_else(|| KonpacError::Invalid("В манифесте нет секции [index]".to_string()))?;
+        let field = |key: &str| section.get(key).ok_or_else(|| KonpacError::Invalid(format!("В манифесте нет поля {}", key)));
+        Ok(IndexManifest {
+            format: field("format")?.parse()?,
+            generated: field("generated")?.parse()?,
+            size: field("size")?.parse()?,
+            sha256: field("sha256")?.to_string(),
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, KonpacError> {
+        IndexManifest::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut ini = Ini::new();
+        ini.with_section(Some("index"))
+            .set("format", self.format.to_string())
+            .set("generated", self.generated.to_string())
+            .set("size", self.size.to_string())
+            .set("sha256", self.sha256.as_str());
+        ini.write_to_file(path)
+    }
+
+    fn expected(&self) -> Expected {
+        Expected { size: Some(self.size), sha256: Some(self.sha256.clone()), max_size: None }
+    }
+}
+
+// Время генерации индекса, уже опубликованного в каталоге: по packages.db и манифесту, 0 для нового репозитория.
+// Пересобранный индекс должен получить отметку больше, иначе клиенты примут его за откат
+pub fn published_stamp(dir: &Path) -> u64 {
+    let db_stamp = SyncDb::open(&dir.join(LEGACY_INDEX_FILE))
+        .and_then(|db| db.stamp())
+        .ok()
+        .flatten()
+        .map(|(_, generated)| generated);
+    let manifest_stamp = IndexManifest::load(&dir.join(MANIFEST_FILE)).ok().map(|manifest| manifest.generated);
+    db_stamp.max(manifest_stamp).unwrap_or(0)
+}
+
+// Путь рядом с файлом с дополнительным расширением
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// Публикация сжатого индекса и манифеста рядом с packages.db.
+// Манифест записывается последним, чтобы он никогда не описывал еще не записанный индекс
+pub fn publish_index(db_path: &Path) -> Result<IndexManifest, KonpacError> {
+    let dir = db_path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let generated = SyncDb::open(db_path)?.stamp()?
+        .map(|(_, generated)| generated)
+        .ok_or_else(|| KonpacError::Invalid("Индекс не содержит отметки формата".to_string()))?;
+
+    let index_path = dir.join(INDEX_FILE);
+    let index_tmp = with_suffix(&index_path, ".tmp");
+    zstd::stream::copy_encode(File::open(db_path)?, File::create(&index_tmp)?, 19)?;
+    fs::rename(&index_tmp, &index_path)?;
+
+    let manifest = IndexManifest {
+        format: INDEX_FORMAT,
+        generated,
+        size: fs::metadata(&index_path)?.len(),
+        sha256: file_sha256(&index_path)?,
+    };
+    let manifest_path = dir.join(MANIFEST_FILE);
+    let manifest_tmp = with_suffix(&manifest_path, ".tmp");
+    manifest.write(&manifest_tmp)?;
+    fs::rename(&manifest_tmp, &manifest_path)?;
+    info!("Опубликован индекс формата {}, сгенерирован {}", manifest.format, manifest.generated);
+    Ok(manifest)
+}
+
+// Синхронизированный индекс репозитория системы с корнем root
+pub fn index_path(root: &Path, repo: &Repository) -> PathBuf {
+    rooted(root, SYNC_PATH).join(format!("{}.db", repo.name))
+}
+
+// Последний принятый манифест репозитория; по нему отсекаются откаты на старые индексы
+fn seen_manifest_path(root: &Path, repo: &Repository) -> PathBuf {
+    rooted(root, SYNC_PATH).join(format!("{}.manifest", repo.name))
+}
+
+pub fn seen_manifest(root: &Path, repo: &Repository) -> Option<IndexManifest> {
+    IndexManifest::load(&seen_manifest_path(root, repo)).ok()
+}
+
+// Проверка манифеста зеркала относительно последнего принятого
+pub fn check_manifest(manifest: &IndexManifest, seen: Option<&IndexManifest>) -> Result<(), KonpacError> {
+    if manifest.format > INDEX_FORMAT {
+        return Err(KonpacError::Conflict(format!("Формат индекса {} не поддерживается, обновите konpac (поддерживается до {})", manifest.format, INDEX_FORMAT)));
+    }
+    if let Some(seen) = seen {
+        let same = manifest.generated == seen.generated && manifest.sha256 == seen.sha256;
+        if manifest.generated <= seen.generated && !same {
+            return Err(KonpacError::Conflict(format!("Индекс {} не новее уже полученного {}, возможна атака отката", manifest.generated, seen.generated)));
+        }
+    }
+    Ok(())
+}
+
+// Загрузка манифеста с зеркала; без повторов, отсутствие манифеста означает репозиторий старого формата
+pub async fn fetch_manifest(transports: &Transports, root: &Path, repo: &Repository, mirror: &str) -> Result<IndexManifest, KonpacError> {
+    let manifest_tmp = rooted(root, SYNC_PATH).join(format!("{}.manifest.new", repo.name));
+    let expected = Expected { max_size: Some(MAX_MANIFEST_SIZE), ..Expected::default() };
+    download(transports, &join_url(mirror, MANIFEST_FILE), &manifest_tmp, &expected, &ProgressBar::hidden()).await?;
+    let manifest = IndexManifest::load(&manifest_tmp);
+    let _ = fs::remove_file(&manifest_tmp);
+    manifest
+}
+
+// Загрузка индекса с того же зеркала, что и манифест, чтобы они гарантированно соответствовали друг другу
+async fn sync_from_mirror(fetcher: &Fetcher, transports: &Transports, repo: &Repository, mirror: &str, manifest: &IndexManifest, db_path: &Path) -> Result<(), KonpacError> {
+    let mirror = vec![mirror.to_string()];
+    let compressed = rooted(fetcher.root(), SYNC_PATH).join(format!("{}.db.zst", repo.name));
+    let pb = progress_bar(&format!("{}/{}", repo.name, INDEX_FILE), Some(manifest.size));
+    let result = fetch_from_mirrors(fetcher, transports, &mirror, INDEX_FILE, &compressed, &manifest.expected(), &pb).await;
+    pb.finish_and_clear();
+    result?;
+
+    let db_tmp = with_suffix(db_path, ".tmp");
+    let unpacked = zstd::stream::copy_decode(File::open(&compressed)?, File::create(&db_tmp)?);
+    let _ = fs::remove_file(&compressed);
+    unpacked?;
+    // Отметка внутри индекса должна совпадать с манифестом
+    let stamp = SyncDb::open(&db_tmp)?.stamp()?;
+    if stamp != Some((manifest.format, manifest.generated)) {
+        let _ = fs::remove_file(&db_tmp);
+        return Err(KonpacError::Checksum(format!("Отметка индекса {:?} не совпадает с манифестом", stamp)));
+    }
+    fs::rename(&db_tmp, db_path)?;
+    Ok(())
+}
+
+// Синхронизация индекса репозитория в SYNC_PATH внутри корня операции.
+// Репозитории без манифеста загружаются по-старому, пока от них не получен ни один манифест
+pub async fn sync_index(fetcher: &Fetcher, repo: &Repository) -> Result<PathBuf, KonpacError> {
+    let root = fetcher.root();
+    fs::create_dir_all(rooted(root, SYNC_PATH))?;
+    let db_path = index_path(root, repo);
+    let seen = seen_manifest(root, repo);
+    let transports = fetcher.transports(repo)?;
+
+    let mut last_error: Option<KonpacError> = None;
+    for mirror in fetcher.health(|health| health.order(&repo.mirrors)) {
+        let manifest = match fetch_manifest(&transports, root, repo, &mirror).await {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                info!("Манифест {} на зеркале {} недоступен: {}", repo.name, mirror, e);
+                continue;
+            }
+        };
+        // Отстающее или подмененное зеркало пропускается, индекс берется со следующего
+        let result = match check_manifest(&manifest, seen.as_ref()) {
+            Ok(()) if seen.as_ref() == Some(&manifest) && db_path.exists() => {
+                info!("Индекс {} не изменился", repo.name);
+                return Ok(db_path);
+            }
+            Ok(()) => sync_from_mirror(fetcher, &transports, repo, &mirror, &manifest, &db_path).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                manifest.write(&seen_manifest_path(root, repo))?;
+                return Ok(db_path);
+            }
+            Err(e) => {
+                warn!("Индекс {} с зеркала {} не принят: {}", repo.name, mirror, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    // Репозиторий, уже публиковавший манифест, не может вернуться к старому формату
+    if let Some(e) = last_error {
+        return Err(e.context(format!("Не удалось получить индекс {}", repo.name)));
+    }
+    if seen.is_some() {
+        return Err(KonpacError::Network(format!("Не удалось получить индекс {}: манифест недоступен", repo.name)));
+    }
+
+    info!("Манифест {} не найден, загрузка {}", repo.name, LEGACY_INDEX_FILE);
+    let pb = progress_bar(&format!("{}/{}", repo.name, LEGACY_INDEX_FILE), None);
+    let result = fetch_from_mirrors(fetcher, &transports, &repo.mirrors, LEGACY_INDEX_FILE, &db_path, &Expected::default(), &pb).await;
+    pb.finish_and_clear();
+    result?;
+    Ok(db_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(format: u32, generated: u64, sha256: &str) -> IndexManifest {
+        IndexManifest { format, generated, size: 1, sha256: sha256.to_string() }
+    }
+
+    #[test]
+    fn check_manifest_rejects_rollback() {
+        let seen = manifest(INDEX_FORMAT, 100, "aa");
+        // Первый манифест и более новый принимаются
+        assert!(check_manifest(&seen, None).is_ok());
+        assert!(check_manifest(&manifest(INDEX_FORMAT, 101, "bb"), Some(&seen)).is_ok());
+        // Тот же индекс с другого зеркала - не откат
+        assert!(check_manifest(&manifest(INDEX_FORMAT, 100, "aa"), Some(&seen)).is_ok());
+        // Старее или с тем же временем, но другим содержимым - откат или подмена
+        assert!(matches!(check_manifest(&manifest(INDEX_FORMAT, 99, "aa"), Some(&seen)), Err(KonpacError::Conflict(_))));
+        assert!(matches!(check_manifest(&manifest(INDEX_FORMAT, 100, "bb"), Some(&seen)), Err(KonpacError::Conflict(_))));
+        // Формат новее поддерживаемого не принимается даже без принятого ранее манифеста
+        assert!(matches!(check_manifest(&manifest(INDEX_FORMAT + 1, 200, "cc"), None), Err(KonpacError::Conflict(_))));
+    }
+}
@@ -0,0 +1,262 @@
+use crate::error::KonpacError;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use indicatif::ProgressBar;
+use ini::Ini;
+use log::{info, warn, error};
+use url::{ParseError, Url};
+use crate::config::Config;
+use crate::consts::paths::{rooted, MIRRORS_STATE_FILE, SYNC_PATH};
+use crate::package::backup::file_sha256;
+use crate::db::RepoPackage;
+use crate::repo::download::{download, Expected};
+use crate::repo::transport::Transports;
+use crate::repo::index::{fetch_manifest, index_path, seen_manifest, LEGACY_INDEX_FILE};
+use crate::repo::utils::{expand_url, read_mirrorlist, Repository};
+
+// Количество попыток на одно зеркало и начальная пауза между ними
+const RETRIES: u32 = 3;
+const BACKOFF: Duration = Duration::from_millis(500);
+// Сколько секунд зеркало после сбоя считается нездоровым
+const FAILURE_COOLDOWN: u64 = 3600;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Склейка адреса зеркала и относительного пути
+pub fn join_url(base: &str, rel: &str) -> String {
+    if rel.is_empty() || base.ends_with('/') {
+        format!("{}{}", base, rel)
+    } else {
+        format!("{}/{}", base, rel)
+    }
+}
+
+// Состояние зеркал между запусками: секция на каждый адрес
+pub struct MirrorHealth {
+    path: PathBuf,
+    state: Ini,
+    // Есть несохраненные изменения
+    changed: bool,
+}
+
+impl MirrorHealth {
+    // Состояние из MIRRORS_STATE_FILE внутри root
+    pub fn load(root: &Path) -> Self {
+        let path = rooted(root, MIRRORS_STATE_FILE);
+        let state = Ini::load_from_file(&path).unwrap_or_default();
+        MirrorHealth { path, state, changed: false }
+    }
+
+    pub fn save(&mut self) {
+        if !self.changed {
+            return;
+        }
+        match self.state.write_to_file(&self.path) {
+            Ok(()) => self.changed = false,
+            Err(e) => error!("Не удалось сохранить состояние зеркал: {}", e),
+        }
+    }
+
+    fn value(&self, mirror: &str, key: &str) -> Option<u64> {
+        self.state.get_from(Some(mirror), key).and_then(|value| value.parse().ok())
+    }
+
+    fn set(&mut self, mirror: &str, key: &str, value: u64) {
+        self.state.with_section(Some(mirror)).set(key, value.to_string());
+        self.changed = true;
+    }
+
+    pub fn record_success(&mut self, mirror: &str) {
+        self.set(mirror, "failures", 0);
+        self.set(mirror, "last_success", now());
+    }
+
+    pub fn record_failure(&mut self, mirror: &str) {
+        let failures = self.value(mirror, "failures").unwrap_or(0) + 1;
+        self.set(mirror, "failures", failures);
+        self.set(mirror, "last_failure", now());
+    }
+
+    pub fn record_latency(&mut self, mirror: &str, latency: Duration) {
+        self.set(mirror, "latency_ms", latency.as_millis() as u64);
+    }
+
+    // Недавно сбоившее зеркало
+    fn is_failing(&self, mirror: &str) -> bool {
+        self.value(mirror, "failures").unwrap_or(0) > 0
+            && now().saturating_sub(self.value(mirror, "last_failure").unwrap_or(0)) < FAILURE_COOLDOWN
+    }
+
+    // Порядок опроса: здоровые раньше сбоивших, затем по задержке, затем как в конфигурации
+    pub fn order(&self, mirrors: &[String]) -> Vec<String> {
+        let mut ordered = mirrors.to_vec();
+        ordered.sort_by_key(|mirror| (self.is_failing(mirror), self.value(mirror, "latency_ms").unwrap_or(u64::MAX)));
+        ordered
+    }
+}
+
+// Загрузки одной операции: транспорты каждого репозитория, в том числе его HTTP клиент,
+// создаются при первом обращении и используются всеми следующими загрузками.
+// Состояние зеркал общее для параллельных загрузок и записывается на диск через save в конце операции
+pub struct Fetcher {
+    config: Config,
+    // Корень системы: индексы и состояние зеркал хранятся внутри него
+    root: PathBuf,
+    transports: Mutex<HashMap<String, Arc<Transports>>>,
+    health: Mutex<MirrorHealth>,
+}
+
+impl Fetcher {
+    pub fn new(config: Config, root: &Path) -> Self {
+        Fetcher {
+            config,
+            root: root.to_path_buf(),
+            transports: Mutex::new(HashMap::new()),
+            health: Mutex::new(MirrorHealth::load(root)),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Изменение состояния зеркал; блокировка не удерживается между загрузками
+    pub fn health<T>(&self, f: impl FnOnce(&mut MirrorHealth) -> T) -> T {
+        f(&mut self.health.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn save(&self) {
+        self.health(|health| health.save());
+    }
+
+    pub fn transports(&self, repo: &Repository) -> Result<Arc<Transports>, KonpacError> {
+        let mut transports = self.transports.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(existing) = transports.get(&repo.name) {
+            return Ok(existing.clone());
+        }
+        let created = Arc::new(Transports::new(&repo.http, &self.config)?);
+        transports.insert(repo.name.clone(), created.clone());
+        Ok(created)
+    }
+}
+
+// Загрузка с повторами и переходом на следующее зеркало
+pub async fn fetch_from_mirrors(fetcher: &Fetcher, transports: &Transports, mirrors: &[String], rel: &str, file_name: &Path, expected: &Expected, pb: &ProgressBar) -> Result<(), KonpacError> {
+    let mut last_error = KonpacError::NotFound("Нет зеркал для загрузки".to_string());
+    for mirror in fetcher.health(|health| health.order(mirrors)) {
+        let url = join_url(&mirror, rel);
+        for attempt in 0..RETRIES {
+            if attempt > 0 {
+                tokio::time::sleep(BACKOFF * 2u32.pow(attempt - 1)).await;
+            }
+            match download(transports, &url, file_name, expected, pb).await {
+                Ok(()) => {
+                    fetcher.health(|health| health.record_success(&mirror));
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Попытка {}/{} загрузки {} не удалась: {}", attempt + 1, RETRIES, url, e);
+                    last_error = e;
+                }
+            }
+        }
+        error!("Зеркало {} недоступно, переход к следующему", mirror);
+        fetcher.health(|health| health.record_failure(&mirror));
+    }
+    Err(last_error)
+}
+
+// Загрузка файла из репозитория: адрес внутри зеркала можно взять с любого другого зеркала.
+// Абсолютный адрес вне зеркал репозитория отклоняется: индекс не может направить загрузку на другой хост
+pub async fn fetch_repo_file(fetcher: &Fetcher, repo: &Repository, url: &str, expected: &Expected, file_name: &Path, pb: &ProgressBar) -> Result<(), KonpacError> {
+    let transports = fetcher.transports(repo)?;
+    // Относительный адрес отсчитывается от каталога индекса на любом зеркале
+    if let Err(ParseError::RelativeUrlWithoutBase) = Url::parse(url) {
+        return fetch_from_mirrors(fetcher, &transports, &repo.mirrors, url, file_name, expected, pb).await;
+    }
+    // Старые индексы хранят абсолютные адреса
+    for mirror in &repo.mirrors {
+        let base = if mirror.ends_with('/') { mirror.clone() } else { format!("{}/", mirror) };
+        if let Some(rel) = url.strip_prefix(&base) {
+            return fetch_from_mirrors(fetcher, &transports, &repo.mirrors, rel, file_name, expected, pb).await;
+        }
+    }
+    Err(KonpacError::Invalid(format!("Адрес {} не относится ни к одному зеркалу репозитория {}", url, repo.name)))
+}
+
+// Загрузка архива пакета
+pub async fn fetch_package(fetcher: &Fetcher, repo: &Repository, package: &RepoPackage, file_name: &Path, pb: &ProgressBar) -> Result<(), KonpacError> {
+    fetch_repo_file(fetcher, repo, &package.url, &package.expected(), file_name, pb).await
+}
+
+// Результат проверки одного зеркала
+pub struct MirrorRank {
+    pub mirror: String,
+    pub latency: Option<Duration>,
+    // Индекс зеркала совпадает с локальной копией
+    pub up_to_date: bool,
+}
+
+// Замер задержки зеркал по загрузке манифеста индекса и сравнение его с последним принятым.
+// У зеркал старого формата без манифеста загружается и сравнивается с локальной копией сам индекс.
+// Список mirrorlist переписывается в новом порядке
+pub async fn rank_mirrors(fetcher: &Fetcher, repo: &Repository) -> Result<Vec<MirrorRank>, KonpacError> {
+    let root = fetcher.root();
+    let seen = seen_manifest(root, repo);
+    let local_sha = file_sha256(&index_path(root, repo)).ok();
+    let probe = rooted(root, SYNC_PATH).join(format!("{}.rank.db", repo.name));
+    fs::create_dir_all(rooted(root, SYNC_PATH))?;
+    let transports = fetcher.transports(repo)?;
+    let pb = ProgressBar::hidden();
+
+    let mut ranks = Vec::new();
+    for mirror in &repo.mirrors {
+        let started = Instant::now();
+        let result = match fetch_manifest(&transports, root, repo, mirror).await {
+            Ok(manifest) => Ok(seen.as_ref().is_none_or(|seen| manifest.generated >= seen.generated)),
+            Err(_) => download(&transports, &join_url(mirror, LEGACY_INDEX_FILE), &probe, &Expected::default(), &pb).await
+                .map(|()| seen.is_none() && local_sha.as_ref().is_none_or(|local_sha| file_sha256(&probe).ok().as_ref() == Some(local_sha))),
+        };
+        let rank = match result {
+            Ok(up_to_date) => {
+                let latency = started.elapsed();
+                fetcher.health(|health| {
+                    health.record_latency(mirror, latency);
+                    health.record_success(mirror);
+                });
+                MirrorRank { mirror: mirror.clone(), latency: Some(latency), up_to_date }
+            }
+            Err(e) => {
+                warn!("Зеркало {} недоступно: {}", mirror, e);
+                fetcher.health(|health| health.record_failure(mirror));
+                MirrorRank { mirror: mirror.clone(), latency: None, up_to_date: false }
+            }
+        };
+        ranks.push(rank);
+    }
+    let _ = fs::remove_file(&probe);
+
+    // Сначала актуальные, затем по задержке; недоступные в конце
+    ranks.sort_by_key(|rank| (!rank.up_to_date, rank.latency.unwrap_or(Duration::MAX)));
+
+    // В файле остаются исходные строки с $repo и $arch, меняется только их порядок
+    if let Some(mirrorlist) = &repo.mirrorlist {
+        let mut servers = read_mirrorlist(mirrorlist)?;
+        servers.sort_by_key(|server| {
+            let url = expand_url(server, &repo.name, &repo.arch);
+            ranks.iter().position(|rank| rank.mirror == url).unwrap_or(usize::MAX)
+        });
+        let mut content = String::from("# Отсортировано konpac mirror rank\n");
+        for server in servers {
+            content.push_str(&format!("Server = {}\n", server));
+        }
+        fs::write(mirrorlist, content)?;
+        info!("Список зеркал {:?} переупорядочен", mirrorlist);
+    }
+    Ok(ranks)
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use log::{info, warn, error};
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use walkdir::WalkDir;
+use percent_encoding::percent_decode_str;
+use crate::error::KonpacError;
+use crate::package::archive::is_package_file;
+use crate::repo::gen::generate_repo;
+
+// Ограничение на размер заголовков запроса
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+// Сколько ждать заголовков запроса и каждой записи ответа, прежде чем закрыть соединение
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Запрос клиента: метод, путь и заголовки (имена в нижнем регистре)
+struct Request {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+}
+
+// Операция с клиентом, которая должна завершиться за IO_TIMEOUT
+async fn with_timeout<T>(operation: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    tokio::time::timeout(IO_TIMEOUT, operation).await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Истекло время ожидания клиента")))
+}
+
+// Чтение заголовков: весь запрос читается не дальше MAX_HEADER_BYTES, поэтому даже одна строка без конца
+// не растет без ограничения
+async fn read_request(reader: &mut BufReader<TcpStream>) -> Result<Option<Request>, KonpacError> {
+    let mut reader = reader.take(MAX_HEADER_BYTES as u64);
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(KonpacError::Invalid("Слишком длинная или оборванная строка запроса".to_string()));
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(KonpacError::Invalid("Некорректная строка запроса".to_string()));
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).await?;
+        if !line.ends_with('\n') {
+            return Err(KonpacError::Invalid("Слишком длинные или оборванные заголовки".to_string()));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    Ok(Some(Request { method, path, headers }))
+}
+
+// Путь запроса внутри корня; "..", абсолютные компоненты и выход по ссылкам запрещены
+fn resolve_path(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let path = request_path.split(['?', '#']).next().unwrap_or("");
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
+    let mut resolved = root.to_path_buf();
+    for component in Path::new(decoded.as_ref()).components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::RootDir | Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    let canonical = resolved.canonicalize().ok()?;
+    canonical.starts_with(root).then_some(canonical)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) if name.ends_with(".db") => "application/vnd.sqlite3",
+        Some(name) if name.ends_with(".kpkg") => "application/x-konpac-package",
+        Some(name) if name.ends_with(".tar.gz") || name.ends_with(".tgz") => "application/gzip",
+        Some(name) if name.ends_with(".zst") => "application/zstd",
+        Some(name) if name.ends_with(".tar.xz") => "application/x-xz",
+        Some(name) if name.ends_with(".tar") => "application/x-tar",
+        Some(name) if name.ends_with(".txt") || name.ends_with(".manifest") || name == "mirrorlist" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+// Диапазон "bytes=a-b", "bytes=a-" или "bytes=-n"; несколько диапазонов не поддерживаются
+fn parse_range(header: &str, size: u64) -> Option<(u64, u64)> {
+    let spec = header.strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (size.saturating_sub(suffix), size.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, size.checked_sub(1)?),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(size.checked_sub(1)?)),
+    };
+    (start <= end && start < size).then_some((start, end))
+}
+
+async fn write_head(stream: &mut TcpStream, status: &str, headers: &[(&str, String)]) -> std::io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\nServer: konpac\r\nConnection: close\r\n", status);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    with_timeout(stream.write_all(head.as_bytes())).await
+}
+
+async fn respond_status(stream: &mut TcpStream, status: &str) -> std::io::Result<()> {
+    write_head(stream, status, &[("Content-Length", "0".to_string())]).await
+}
+
+// Ответ на один запрос; возвращается код для журнала
+async fn respond(stream: &mut TcpStream, root: &Path, request: &Request) -> Result<&'static str, KonpacError> {
+    let head_only = match request.method.as_str() {
+        "GET" => false,
+        "HEAD" => true,
+        _ => {
+            respond_status(stream, "405 Method Not Allowed").await?;
+            return Ok("405");
+        }
+    };
+    let Some(path) = resolve_path(root, &request.path).filter(|path| path.is_file()) else {
+        respond_status(stream, "404 Not Found").await?;
+        return Ok("404");
+    };
+
+    let mut file = File::open(&path).await?;
+    let metadata = file.metadata().await?;
+    let size = metadata.len();
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+    // ETag меняется вместе с размером или временем изменения файла
+    let etag = format!("\"{:x}-{:x}\"", size, modified.as_nanos());
+
+    if request.headers.get("if-none-match").is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*")) {
+        write_head(stream, "304 Not Modified", &[("ETag", etag)]).await?;
+        return Ok("304");
+    }
+
+    // Диапазон учитывается, только если If-Range отсутствует или совпадает с текущим ETag
+    let range_header = request.headers.get("range")
+        .filter(|_| request.headers.get("if-range").is_none_or(|tag| *tag == etag));
+    let (status, start, end) = match range_header {
+        Some(range) => match parse_range(range, size) {
+            Some((start, end)) => ("206 Partial Content", start, end),
+            None => {
+                write_head(stream, "416 Range Not Satisfiable", &[
+                    ("Content-Range", format!("bytes */{}", size)),
+                    ("Content-Length", "0".to_string()),
+                ]).await?;
+                return Ok("416");
+            }
+        },
+        None => ("200 OK", 0, size.saturating_sub(1)),
+    };
+    let length = if size == 0 { 0 } else { end - start + 1 };
+
+    let mut headers = vec![
+        ("Content-Type", content_type(&path).to_string()),
+        ("Content-Length", length.to_string()),
+        ("Accept-Ranges", "bytes".to_string()),
+        ("ETag", etag),
+    ];
+    if status.starts_with("206") {
+        headers.push(("Content-Range", format!("bytes {}-{}/{}", start, end, size)));
+    }
+    write_head(stream, status, &headers).await?;
+    if !head_only && length > 0 {
+        file.seek(SeekFrom::Start(start)).await?;
+        // Ожидание ограничено для каждой записи, а не для всего ответа: большой пакет может идти долго
+        let mut body = file.take(length);
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let read = body.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            with_timeout(stream.write_all(&buffer[..read])).await?;
+        }
+    }
+    Ok(if status.starts_with("206") { "206" } else { "200" })
+}
+
+async fn handle_connection(stream: TcpStream, root: &Path) -> Result<(), KonpacError> {
+    let peer = stream.peer_addr()?;
+    let mut reader = BufReader::new(stream);
+    let request = match tokio::time::timeout(IO_TIMEOUT, read_request(&mut reader)).await {
+        Ok(Ok(Some(request))) => request,
+        Ok(Ok(None)) => return Ok(()),
+        Err(_) => {
+            respond_status(reader.get_mut(), "408 Request Timeout").await?;
+            return Err(KonpacError::Network(format!("{} не прислал запрос за {} с", peer, IO_TIMEOUT.as_secs())));
+        }
+        Ok(Err(e)) => {
+            respond_status(reader.get_mut(), "400 Bad Request").await?;
+            return Err(e);
+        }
+    };
+    let status = respond(reader.get_mut(), root, &request).await?;
+    info!("{} {} {} {}", peer, request.method, request.path, status);
+    Ok(())
+}
+
+// Отпечаток пакетов в каталоге: путь, размер и время изменения
+fn packages_fingerprint(root: &Path) -> Vec<(PathBuf, u64, u128)> {
+    let mut fingerprint: Vec<_> = WalkDir::new(root)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file() && is_package_file(entry.path()))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+            Some((entry.into_path(), metadata.len(), modified))
+        })
+        .collect();
+    fingerprint.sort();
+    fingerprint
+}
+
+async fn rebuild_index(root: &Path, deltas: bool) {
+    let dir = root.to_path_buf();
+    match tokio::task::spawn_blocking(move || generate_repo(&dir, deltas)).await {
+        Ok(Ok(summary)) if summary.failures.is_empty() => info!("Пересборка индекса завершена"),
+        Ok(Ok(summary)) => error!("Пересборка индекса завершена с ошибками: {}", summary.failures.join("; ")),
+        Ok(Err(e)) => error!("Ошибка пересборки индекса: {}", e),
+        Err(e) => error!("Ошибка пересборки индекса: {}", e),
+    }
+}
+
+// Пересборка индекса, когда пакеты в каталоге меняются; отсутствующий индекс собирается сразу
+async fn watch_packages(root: PathBuf, interval: Duration, deltas: bool) {
+    let mut known = packages_fingerprint(&root);
+    if !root.join("packages.db").exists() {
+        info!("В {:?} нет packages.db, сборка индекса: Подготовка", root);
+        rebuild_index(&root, deltas).await;
+    }
+    loop {
+        tokio::time::sleep(interval).await;
+        let current = packages_fingerprint(&root);
+        if current == known {
+            continue;
+        }
+        info!("Пакеты в {:?} изменились, пересборка индекса: Подготовка", root);
+        rebuild_index(&root, deltas).await;
+        known = current;
+    }
+}
+
+// Раздача каталога репозитория по HTTP: индекс и пакеты с поддержкой Range и ETag
+pub async fn serve_repo(dir: &Path, bind: &str, watch: Option<Duration>, deltas: bool) -> Result<(), KonpacError> {
+    let root = dir.canonicalize().map_err(|e| KonpacError::from(e).context(format!("Каталог {:?} недоступен", dir)))?;
+    if watch.is_none() && !root.join("packages.db").exists() {
+        warn!("В {:?} нет packages.db, соберите его через --gen-repo или запустите с --watch", root);
+    }
+    if let Some(interval) = watch {
+        tokio::spawn(watch_packages(root.clone(), interval, deltas));
+    }
+
+    let listener = TcpListener::bind(bind).await?;
+    info!("Репозиторий {:?} доступен по адресу http://{}/", root, listener.local_addr()?);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let root = root.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &root).await {
+                warn!("Ошибка обработки запроса: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::package::stage::WorkDir;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+        assert_eq!(parse_range("bytes=900-", 1000), Some((900, 999)));
+        // Конец за пределами файла обрезается
+        assert_eq!(parse_range("bytes=990-2000", 1000), Some((990, 999)));
+        // Последние n байт, в том числе больше размера файла
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some((0, 999)));
+
+        for malformed in ["bytes=", "bytes=-", "bytes=a-b", "bytes=5", "items=0-1", "bytes=0-1,5-6", "bytes=500-100", "bytes=1000-", "bytes=--1"] {
+            assert_eq!(parse_range(malformed, 1000), None, "{}", malformed);
+        }
+        // В пустом файле нечего отдавать
+        assert_eq!(parse_range("bytes=0-", 0), None);
+        assert_eq!(parse_range("bytes=-1", 0), None);
+    }
+
+    #[test]
+    fn paths_stay_inside_root() {
+        let work_dir = WorkDir::temp("serve-paths");
+        let root = work_dir.path().join("repo");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("packages.db"), "").unwrap();
+        fs::write(root.join("sub/pkg 1.kpkg"), "").unwrap();
+        fs::write(work_dir.path().join("secret"), "").unwrap();
+        std::os::unix::fs::symlink(work_dir.path().join("secret"), root.join("escape")).unwrap();
+        let root = root.canonicalize().unwrap();
+
+        assert_eq!(resolve_path(&root, "/packages.db"), Some(root.join("packages.db")));
+        assert_eq!(resolve_path(&root, "/sub/pkg%201.kpkg?x=1"), Some(root.join("sub/pkg 1.kpkg")));
+        assert_eq!(resolve_path(&root, "/./sub/../packages.db"), None);
+        assert_eq!(resolve_path(&root, "/../secret"), None);
+        assert_eq!(resolve_path(&root, "/%2e%2e/secret"), None);
+        assert_eq!(resolve_path(&root, "/sub/%2E%2E/%2E%2E/secret"), None);
+        assert_eq!(resolve_path(&root, "/escape"), None);
+        assert_eq!(resolve_path(&root, "/missing"), None);
+        assert_eq!(resolve_path(&root, "/%zz"), None);
+    }
+}
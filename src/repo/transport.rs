@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::Duration;
+use futures::future::BoxFuture;
+use indicatif::ProgressBar;
+use log::{info, warn};
+use percent_encoding::percent_decode_str;
+use reqwest::{header, StatusCode};
+use tokio::process::Command;
+use url::Url;
+use crate::config::Config;
+use crate::error::KonpacError;
+use crate::repo::download::{Expected, HttpClient, HttpOptions};
+
+// Максимальная пауза между порциями данных по HTTP
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Способ загрузки файла по схеме URL. Данные пишутся в part;
+// размер и sha256 итогового файла проверяет download, повторы и зеркала - fetch_from_mirrors
+pub trait Transport: Send + Sync {
+    fn fetch<'a>(&'a self, url: &'a Url, part: &'a Path, expected: &'a Expected, pb: &'a ProgressBar) -> BoxFuture<'a, Result<(), KonpacError>>;
+}
+
+// Транспорты, зарегистрированные через библиотеку, например хранилище в памяти для тестов
+static REGISTERED: LazyLock<RwLock<HashMap<String, Arc<dyn Transport>>>> = LazyLock::new(Default::default);
+
+// Регистрация транспорта схемы для всех следующих загрузок процесса; заменяет встроенный и настроенный
+pub fn register_transport(scheme: &str, transport: Arc<dyn Transport>) {
+    REGISTERED.write().unwrap_or_else(|e| e.into_inner()).insert(scheme.to_lowercase(), transport);
+}
+
+// Транспорты одного репозитория: HTTP клиент несет его авторизацию и сертификаты
+pub struct Transports {
+    by_scheme: HashMap<String, Arc<dyn Transport>>,
+}
+
+impl Transports {
+    // Встроенные http, https, file, ssh и sftp, затем программы из [transports] в konpac.conf,
+    // затем зарегистрированные через register_transport
+    pub fn new(http: &HttpOptions, config: &Config) -> Result<Self, KonpacError> {
+        let mut transports = Transports { by_scheme: HashMap::new() };
+        let http: Arc<dyn Transport> = Arc::new(HttpTransport { client: HttpClient::new(http, config)? });
+        transports.register("http", http.clone());
+        transports.register("https", http);
+        transports.register("file", Arc::new(FileTransport));
+        transports.register("ssh", Arc::new(SshTransport { sftp: false }));
+        transports.register("sftp", Arc::new(SshTransport { sftp: true }));
+        for (scheme, helper) in &config.transports {
+            transports.register(scheme, Arc::new(ExecTransport { helper: helper.clone() }));
+        }
+        for (scheme, transport) in REGISTERED.read().unwrap_or_else(|e| e.into_inner()).iter() {
+            transports.register(scheme, transport.clone());
+        }
+        Ok(transports)
+    }
+
+    pub fn register(&mut self, scheme: &str, transport: Arc<dyn Transport>) {
+        self.by_scheme.insert(scheme.to_lowercase(), transport);
+    }
+
+    pub async fn fetch(&self, url: &Url, part: &Path, expected: &Expected, pb: &ProgressBar) -> Result<(), KonpacError> {
+        let transport = self.by_scheme.get(url.scheme())
+            .ok_or_else(|| KonpacError::Invalid(format!("Неподдерживаемая схема URL: {}", url.scheme())))?;
+        transport.fetch(url, part, expected, pb).await
+    }
+}
+
+// Превышение размера, заявленного в индексе, или предельного размера
+fn check_size(written: u64, expected: &Expected) -> Result<(), KonpacError> {
+    match (expected.size, expected.max_size) {
+        (Some(size), _) if written > size => Err(KonpacError::Checksum(format!("Файл больше заявленного в индексе размера: {} > {}", written, size))),
+        (_, Some(max_size)) if written > max_size => Err(KonpacError::Invalid(format!("Файл больше допустимого размера: {} > {}", written, max_size))),
+        _ => Ok(()),
+    }
+}
+
+// Загрузка по HTTP/HTTPS с докачкой через Range
+struct HttpTransport {
+    client: HttpClient,
+}
+
+impl HttpTransport {
+    async fn fetch_http(&self, url: &str, part: &Path, expected: &Expected, pb: &ProgressBar) -> Result<(), KonpacError> {
+        // Без известного sha256 склеенный из двух загрузок файл нечем проверить, поэтому докачка только с ним
+        let offset = match (&expected.sha256, fs::metadata(part)) {
+            (Some(_), Ok(metadata)) => metadata.len(),
+            _ => 0,
+        };
+        if expected.size.is_some_and(|size| offset >= size) {
+            return Ok(());
+        }
+
+        let mut request = self.client.get(url);
+        if offset > 0 {
+            info!("Докачка {} с {} байт", url, offset);
+            request = request.header(header::RANGE, format!("bytes={}-", offset));
+        }
+        let mut response = tokio::time::timeout(IDLE_TIMEOUT, request.send()).await
+            .map_err(|_| KonpacError::Network(format!("Истекло время ожидания ответа от {}", url)))??;
+
+        let (mut file, mut written) = match response.status() {
+            StatusCode::PARTIAL_CONTENT => (OpenOptions::new().append(true).open(part)?, offset),
+            // Сервер уже отдал все байты в прошлый раз
+            StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => return Ok(()),
+            _ => {
+                response = response.error_for_status()?;
+                if offset > 0 {
+                    warn!("Сервер не поддерживает докачку, загрузка {} начинается заново", url);
+                }
+                (File::create(part)?, 0)
+            }
+        };
+
+        let total = response.content_length().map(|length| length + written);
+        if let Some(total) = total {
+            check_size(total, expected)?;
+        }
+        if let Some(total) = total.or(expected.size) {
+            pb.set_length(total);
+        }
+        pb.set_position(written);
+        loop {
+            let chunk = tokio::time::timeout(IDLE_TIMEOUT, response.chunk()).await
+                .map_err(|_| KonpacError::Network(format!("Истекло время ожидания данных от {}", url)))??;
+            let Some(chunk) = chunk else { break };
+            written += chunk.len() as u64;
+            check_size(written, expected)?;
+            file.write_all(&chunk)?;
+            pb.set_position(written);
+        }
+        Ok(())
+    }
+}
+
+impl Transport for HttpTransport {
+    fn fetch<'a>(&'a self, url: &'a Url, part: &'a Path, expected: &'a Expected, pb: &'a ProgressBar) -> BoxFuture<'a, Result<(), KonpacError>> {
+        Box::pin(self.fetch_http(url.as_str(), part, expected, pb))
+    }
+}
+
+// Копирование локального файла
+struct FileTransport;
+
+impl FileTransport {
+    fn fetch_file(url: &Url, part: &Path, expected: &Expected, pb: &ProgressBar) -> Result<(), KonpacError> {
+        let path = url.to_file_path().map_err(|_| KonpacError::Invalid(format!("Некорректный file:// адрес {}", url)))?;
+        let mut source = File::open(path)?;
+        let total = source.metadata()?.len();
+        check_size(total, expected)?;
+        let mut destination = File::create(part)?;
+        pb.set_length(total);
+        pb.set_position(0);
+        let mut buffer = [0u8; 64 * 1024];
+        loop {
+            let read = source.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            destination.write_all(&buffer[..read])?;
+            pb.inc(read as u64);
+        }
+        Ok(())
+    }
+}
+
+impl Transport for FileTransport {
+    fn fetch<'a>(&'a self, url: &'a Url, part: &'a Path, expected: &'a Expected, pb: &'a ProgressBar) -> BoxFuture<'a, Result<(), KonpacError>> {
+        Box::pin(async move { FileTransport::fetch_file(url, part, expected, pb) })
+    }
+}
+
+// Размер загруженного внешней программой файла на полосе прогресса
+fn finish_external(part: &Path, expected: &Expected, pb: &ProgressBar) -> Result<(), KonpacError> {
+    let size = fs::metadata(part)?.len();
+    check_size(size, expected)?;
+    pb.set_length(size);
+    pb.set_position(size);
+    Ok(())
+}
+
+// Загрузка по ssh://[user@]host[:port]/path через scp; sftp:// принудительно использует протокол SFTP.
+// Ключи и known_hosts берутся из настроек ssh, пароль не запрашивается
+struct SshTransport {
+    sftp: bool,
+}
+
+impl SshTransport {
+    async fn fetch_ssh(&self, url: &Url, part: &Path, expected: &Expected, pb: &ProgressBar) -> Result<(), KonpacError> {
+        let host = url.host_str().ok_or_else(|| KonpacError::Invalid(format!("Не указан хост в {}", url)))?;
+        // Хост или пользователь с '-' в начале scp принял бы за свой ключ, например -oProxyCommand
+        if host.starts_with('-') || url.username().starts_with('-') {
+            return Err(KonpacError::Invalid(format!("Недопустимый хост или пользователь в {}", url)));
+        }
+        let path = percent_decode_str(url.path()).decode_utf8().map_err(|_| KonpacError::Invalid(format!("Некорректный путь в {}", url)))?;
+        let source = match url.username() {
+            "" => format!("{}:{}", host, path),
+            user => format!("{}@{}:{}", user, host, path),
+        };
+
+        let mut command = Command::new("scp");
+        command.args(["-q", "-o", "BatchMode=yes"]);
+        if self.sftp {
+            command.arg("-s");
+        }
+        if let Some(port) = url.port() {
+            command.arg("-P").arg(port.to_string());
+        }
+        let output = command.arg("--").arg(&source).arg(part).output().await
+            .map_err(|e| KonpacError::from(e).context("Не удалось запустить scp"))?;
+        if !output.status.success() {
+            return Err(KonpacError::Network(format!("scp {}: {}", source, String::from_utf8_lossy(&output.stderr).trim())));
+        }
+        finish_external(part, expected, pb)
+    }
+}
+
+impl Transport for SshTransport {
+    fn fetch<'a>(&'a self, url: &'a Url, part: &'a Path, expected: &'a Expected, pb: &'a ProgressBar) -> BoxFuture<'a, Result<(), KonpacError>> {
+        Box::pin(self.fetch_ssh(url, part, expected, pb))
+    }
+}
+
+// Загрузка внешней программой из konpac.conf: она получает URL и путь, куда записать файл,
+// и завершается с нулевым кодом при успехе
+struct ExecTransport {
+    helper: PathBuf,
+}
+
+impl ExecTransport {
+    async fn fetch_exec(&self, url: &Url, part: &Path, expected: &Expected, pb: &ProgressBar) -> Result<(), KonpacError> {
+        let output = Command::new(&self.helper).arg(url.as_str()).arg(part).output().await
+            .map_err(|e| KonpacError::from(e).context(format!("Не удалось запустить {:?}", self.helper)))?;
+        if !output.status.success() {
+            return Err(KonpacError::Network(format!("{:?} {}: {}", self.helper, url, String::from_utf8_lossy(&output.stderr).trim())));
+        }
+        finish_external(part, expected, pb)
+    }
+}
+
+impl Transport for ExecTransport {
+    fn fetch<'a>(&'a self, url: &'a Url, part: &'a Path, expected: &'a Expected, pb: &'a ProgressBar) -> BoxFuture<'a, Result<(), KonpacError>> {
+        Box::pin(self.fetch_exec(url, part, expected, pb))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::backup::file_sha256;
+    use crate::package::stage::WorkDir;
+    use crate::repo::download::{download, Auth};
+
+    // Файлы в памяти по пути URL
+    struct MemoryTransport {
+        files: HashMap<String, Vec<u8>>,
+    }
+
+    impl Transport for MemoryTransport {
+        fn fetch<'a>(&'a self, url: &'a Url, part: &'a Path, expected: &'a Expected, pb: &'a ProgressBar) -> BoxFuture<'a, Result<(), KonpacError>> {
+            Box::pin(async move {
+                let data = self.files.get(url.path())
+                    .ok_or_else(|| KonpacError::NotFound(format!("{} нет в памяти", url)))?;
+                fs::write(part, data)?;
+                finish_external(part, expected, pb)
+            })
+        }
+    }
+
+    fn memory_transports(files: &[(&str, &[u8])]) -> Transports {
+        let mut transports = Transports { by_scheme: HashMap::new() };
+        let files = files.iter().map(|(path, data)| (path.to_string(), data.to_vec())).collect();
+        transports.register("mem", Arc::new(MemoryTransport { files }));
+        transports
+    }
+
+    #[tokio::test]
+    async fn download_checks_size_and_sha256() {
+        let work_dir = WorkDir::temp("transport-test");
+        let dir = work_dir.path();
+        let data = b"package archive";
+        let transports = memory_transports(&[("/repo/pkg.kpkg", data)]);
+        let source = dir.join("source");
+        fs::write(&source, data).unwrap();
+        let sha256 = file_sha256(&source).unwrap();
+        let target = dir.join("pkg.kpkg");
+        let pb = ProgressBar::hidden();
+
+        let good = Expected { size: Some(data.len() as u64), sha256: Some(sha256.clone()), max_size: None };
+        download(&transports, "mem://host/repo/pkg.kpkg", &target, &good, &pb).await.unwrap();
+        assert_eq!(fs::read(&target).unwrap(), data);
+        fs::remove_file(&target).unwrap();
+
+        let short = Expected { size: Some(data.len() as u64 - 1), sha256: Some(sha256.clone()), max_size: None };
+        let result = download(&transports, "mem://host/repo/pkg.kpkg", &target, &short, &pb).await;
+        assert!(matches!(result, Err(KonpacError::Checksum(_))));
+
+        let limited = Expected { max_size: Some(data.len() as u64 - 1), ..Expected::default() };
+        let result = download(&transports, "mem://host/repo/pkg.kpkg", &target, &limited, &pb).await;
+        assert!(matches!(result, Err(KonpacError::Invalid(_))));
+        let url = Url::from_file_path(&source).unwrap();
+        let result = download(&Transports::new(&HttpOptions::default(), &Config::default()).unwrap(), url.as_str(), &target, &limited, &pb).await;
+        assert!(matches!(result, Err(KonpacError::Invalid(_))));
+
+        let long = Expected { size: Some(data.len() as u64 + 1), sha256: Some(sha256), max_size: None };
+        let result = download(&transports, "mem://host/repo/pkg.kpkg", &target, &long, &pb).await;
+        assert!(matches!(result, Err(KonpacError::Checksum(_))));
+
+        let wrong_sha = Expected { size: Some(data.len() as u64), sha256: Some("0".repeat(64)), max_size: None };
+        let result = download(&transports, "mem://host/repo/pkg.kpkg", &target, &wrong_sha, &pb).await;
+        assert!(matches!(result, Err(KonpacError::Checksum(_))));
+
+        // Итоговый файл появляется только после проверок, .part не остается
+        assert!(!target.exists());
+        assert!(!dir.join("pkg.kpkg.part").exists());
+
+        let result = download(&transports, "unknown://host/repo/pkg.kpkg", &target, &Expected::default(), &pb).await;
+        assert!(matches!(result, Err(KonpacError::Invalid(_))));
+    }
+
+    // HTTP сервер на один запрос: отвечает body и возвращает заголовки запроса (имена в нижнем регистре)
+    async fn serve_once(body: &'static [u8]) -> (String, tokio::task::JoinHandle<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/repo/pkg.kpkg", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).await.unwrap();
+                assert!(read > 0, "запрос оборвался");
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+            String::from_utf8(request).unwrap().to_lowercase()
+        });
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn http_sends_authorization_and_custom_headers() {
+        let work_dir = WorkDir::temp("transport-http");
+        let target = work_dir.path().join("pkg.kpkg");
+        let pb = ProgressBar::hidden();
+
+        let http = HttpOptions {
+            auth: Some(Auth::Basic { username: "user".to_string(), password: Some("pass".to_string()) }),
+            headers: vec![("X-Repo-Token".to_string(), "secret".to_string()), ("X-Client".to_string(), "konpac".to_string())],
+            ..HttpOptions::default()
+        };
+        let transports = Transports::new(&http, &Config::default()).unwrap();
+        let (url, server) = serve_once(b"data").await;
+        download(&transports, &url, &target, &Expected::default(), &pb).await.unwrap();
+        let request = server.await.unwrap();
+        assert!(request.starts_with("get /repo/pkg.kpkg http/1.1\r\n"), "{}", request);
+        // base64("user:pass")
+        assert!(request.contains("\r\nauthorization: basic dxnlcjpwyxnz\r\n"), "{}", request);
+        assert!(request.contains("\r\nx-repo-token: secret\r\n"), "{}", request);
+        assert!(request.contains("\r\nx-client: konpac\r\n"), "{}", request);
+        assert_eq!(fs::read(&target).unwrap(), b"data");
+        fs::remove_file(&target).unwrap();
+
+        let http = HttpOptions { auth: Some(Auth::Bearer("token123".to_string())), ..HttpOptions::default() };
+        let transports = Transports::new(&http, &Config::default()).unwrap();
+        let (url, server) = serve_once(b"data").await;
+        download(&transports, &url, &target, &Expected::default(), &pb).await.unwrap();
+        let request = server.await.unwrap();
+        assert!(request.contains("\r\nauthorization: bearer token123\r\n"), "{}", request);
+
+        // Без авторизации заголовок не отправляется
+        let transports = Transports::new(&HttpOptions::default(), &Config::default()).unwrap();
+        let (url, server) = serve_once(b"data").await;
+        fs::remove_file(&target).unwrap();
+        download(&transports, &url, &target, &Expected::default(), &pb).await.unwrap();
+        assert!(!server.await.unwrap().contains("authorization:"));
+    }
+
+    #[tokio::test]
+    async fn ssh_rejects_option_like_host_and_user() {
+        let work_dir = WorkDir::temp("ssh-test");
+        let part = work_dir.path().join("packages.db.part");
+        let transport = SshTransport { sftp: false };
+        for url in ["ssh://-oProxyCommand=id/repo/packages.db", "ssh://-oProxyCommand=id@host/repo/packages.db"] {
+            let url = Url::parse(url).unwrap();
+            let result = transport.fetch_ssh(&url, &part, &Expected::default(), &ProgressBar::hidden()).await;
+            assert!(matches!(result, Err(KonpacError::Invalid(message)) if message.starts_with("Недопустимый")), "{}", url);
+        }
+        assert!(!part.exists());
+    }
+}
@@ -1,252 +1,404 @@
 // Внешние зависимости
 extern crate reqwest;
-use std::env::consts::ARCH;
-use std::io::Cursor;
-use std::{fs,io,path::Path,result::Result};
-use ini::Ini;
-use rusqlite::{Connection, Row};
-use url::Url;
-use version_compare::Version;
+use std::cmp::Reverse;
+use std::fmt;
+use std::str::FromStr;
+use std::{fs,io,path::{Path, PathBuf},result::Result};
+use std::os::unix::fs::MetadataExt;
+use glob::Pattern;
+use ini::{Ini, Properties};
 use log::{info, error};
-
-// Структура для хранения информации о репозитории
-pub struct Repository {
-    name: String, // Имя репозитория
-    url: String   // URL репозитория
+use crate::error::KonpacError;
+use crate::db::{RepoPackage, SyncDb};
+use crate::repo::index::{index_path, sync_index};
+use crate::repo::mirror::Fetcher;
+use crate::repo::download::{Auth, HttpOptions};
+use crate::package::depencies::version_matches;
+
+// Требования к подписи пакетов репозитория
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigLevel {
+    Never,
+    Optional,
+    Required,
 }
 
-// Структура для хранения информации о пакете из базы данных
-#[derive(Debug)]
-pub struct DbPackageEntry {
-    pub name: String,    // Имя пакета
-    pub version: String, // Версия пакета
-    pub url: String     // URL для скачивания пакета
-}
+impl FromStr for SigLevel {
+    type Err = String;
 
-// Реализация создания DbPackageEntry из строки базы данных
-impl DbPackageEntry {
-    fn from_row(row: &Row) -> Result<Self,Box<dyn std::error::Error>> {
-        Ok(DbPackageEntry {
-            name: row.get(0)?,
-            version: row.get(1)?,
-            url: row.get(2)?
-        })
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "never" => Ok(SigLevel::Never),
+            "optional" => Ok(SigLevel::Optional),
+            "required" => Ok(SigLevel::Required),
+            _ => Err(format!("Неизвестный siglevel '{}'. Используйте never, optional или required", s)),
+        }
     }
 }
 
-// Получение списка репозиториев из конфигурационного файла
-pub fn get_repos(config_file: &Path) -> Vec<Repository> {
-    let repos = Ini::load_from_file(config_file).unwrap();
-    let mut repositories: Vec<Repository> = vec![];
-    
-    // Обработка каждой секции в INI файле
-    for i in repos {
-        let repo_name = i.0.unwrap_or("".to_string());
-        // Замена переменных в URL репозитория
-        let repo_url = i.1.get("url")
-            .unwrap_or("")
-            .replace("$repo", &repo_name)
-            .replace("$arch", ARCH);
-            
-        let repo = Repository{
-            name: repo_name,
-            url: repo_url,
+impl fmt::Display for SigLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SigLevel::Never => "never",
+            SigLevel::Optional => "optional",
+            SigLevel::Required => "required",
         };
-        repositories.push(repo);
+        write!(f, "{}", name)
     }
-    return repositories;
 }
 
+// Структура для хранения информации о репозитории
+#[derive(Clone)]
+pub struct Repository {
+    pub name: String,          // Имя репозитория
+    pub url: String,           // URL репозитория (первое зеркало)
+    pub mirrors: Vec<String>,  // Все зеркала: url, mirror и mirrorlist
+    pub mirrorlist: Option<PathBuf>, // Файл со списком зеркал
+    pub arch: String,          // Архитектура, для которой выбираются пакеты
+    pub priority: i32,         // Приоритет, больший выигрывает
+    pub enabled: bool,         // Отключенные репозитории не используются
+    pub siglevel: SigLevel,    // Требования к подписи пакетов
+    pub arches: Vec<String>,   // Архитектуры, которые обслуживает репозиторий (пусто - все)
+    pub include: Vec<Pattern>, // Разрешенные пакеты (пусто - все)
+    pub exclude: Vec<Pattern>, // Запрещенные пакеты
+    pub http: HttpOptions      // Авторизация, заголовки и сертификаты
+}
+
+impl Repository {
+    // Репозиторий обслуживает целевую архитектуру
+    pub fn serves_arch(&self) -> bool {
+        self.arches.is_empty() || self.arches.iter().any(|arch| *arch == self.arch || arch == ARCH_ANY)
+    }
 
+    // Пакет проходит фильтры include/exclude
+    pub fn allows(&self, package_name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(package_name)))
+            && !self.exclude.iter().any(|p| p.matches(package_name))
+    }
+}
 
+// Архитектура, не зависящая от платформы
+pub const ARCH_ANY: &str = "any";
 
-// Загрузка файла по URL
-pub async fn fetch_url(url: String, file_name: &Path) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Fetching URL: {}", url);
-    let parsed_url = Url::parse(&url)?;
+// Значения параметра: повторяющиеся ключи и списки через пробел или запятую
+pub fn option_list(section: &Properties, key: &str) -> Vec<String> {
+    section.get_all(key)
+        .flat_map(|value| value.split([' ', ',']))
+        .filter(|value| !value.is_empty())
+        .map(|value| value.to_string())
+        .collect()
+}
+
+// Шаблоны имен пакетов; некорректные пропускаются с ошибкой в логе
+fn option_patterns(repo_name: &str, section: &Properties, key: &str) -> Vec<Pattern> {
+    option_list(section, key).iter()
+        .filter_map(|glob| match Pattern::new(glob) {
+            Ok(pattern) => Some(pattern),
+            Err(e) => {
+                error!("Репозиторий {}: некорректный шаблон {} '{}': {}", repo_name, key, glob, e);
+                None
+            }
+        })
+        .collect()
+}
 
-    match parsed_url.scheme() {
-        "http" | "https" => {
-            // Загрузка файла по HTTP/HTTPS
-            let response = reqwest::get(url).await?;
-            let mut file = fs::File::create(file_name)?;
-            let mut content = Cursor::new(response.bytes().await?);
-            io::copy(&mut content, &mut file)?;
+// Разбор значения параметра со значением по умолчанию
+fn option_value<T: FromStr>(repo_name: &str, section: &Properties, key: &str, default: T) -> T
+where T::Err: fmt::Display {
+    match section.get(key).map(str::parse) {
+        Some(Ok(value)) => value,
+        Some(Err(e)) => {
+            error!("Репозиторий {}: некорректное значение {}: {}", repo_name, key, e);
+            default
         }
-        "file" => {
-            // Копирование локального файла
-            let path = Path::new(parsed_url.path());
-            let mut source_file = fs::File::open(path)?;
-            let mut destination_file = fs::File::create(file_name)?;
-            io::copy(&mut source_file, &mut destination_file)?;
+        None => default,
+    }
+}
+
+// Логический параметр: true/false, yes/no, on/off, 1/0
+fn option_bool(repo_name: &str, section: &Properties, key: &str, default: bool) -> bool {
+    match section.get(key).map(|value| value.to_lowercase()) {
+        Some(value) if ["true", "yes", "on", "1"].contains(&value.as_str()) => true,
+        Some(value) if ["false", "no", "off", "0"].contains(&value.as_str()) => false,
+        Some(value) => {
+            error!("Репозиторий {}: некорректное значение {}: {}", repo_name, key, value);
+            default
         }
-        _ => return Err("Unsupported URL scheme".into()),
+        None => default,
     }
+}
 
-    Ok(())
+// Файл с учетными данными принадлежит root и закрыт для остальных
+fn read_credentials(path: &Path) -> Result<Ini, KonpacError> {
+    let metadata = fs::metadata(path)?;
+    if metadata.uid() != 0 || metadata.mode() & 0o077 != 0 {
+        return Err(KonpacError::Permission(format!("{:?} должен принадлежать root и иметь права 600", path)));
+    }
+    Ok(Ini::load_from_file(path)?)
 }
 
-// Поиск последней версии пакета в базе данных
-fn find_package(
-    db_path: &Path,
-    package_name: &str,
-) -> Result<Option<DbPackageEntry>, Box<dyn std::error::Error>> {
-    let conn = Connection::open(db_path)?;
+// Авторизация репозитория: auth = basic (username, password) или bearer (token)
+// из одноименной секции файла учетных данных
+fn repo_auth(repo_name: &str, section: &Properties, credentials_file: &Path) -> Option<Auth> {
+    let kind = section.get("auth")?;
+    if kind == "none" {
+        return None;
+    }
+    let credentials = match read_credentials(credentials_file) {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            error!("Репозиторий {}: учетные данные недоступны: {}", repo_name, e);
+            return None;
+        }
+    };
+    let Some(values) = credentials.section(Some(repo_name)) else {
+        error!("Репозиторий {}: нет секции [{}] в {:?}", repo_name, repo_name, credentials_file);
+        return None;
+    };
+    let auth = match kind {
+        "basic" => values.get("username").map(|username| Auth::Basic {
+            username: username.to_string(),
+            password: values.get("password").map(|password| password.to_string()),
+        }),
+        "bearer" => values.get("token").map(|token| Auth::Bearer(token.to_string())),
+        _ => {
+            error!("Репозиторий {}: неизвестный способ авторизации '{}'. Используйте basic, bearer или none", repo_name, kind);
+            return None;
+        }
+    };
+    if auth.is_none() {
+        error!("Репозиторий {}: для auth = {} не хватает учетных данных", repo_name, kind);
+    }
+    auth
+}
 
-    // SQL запрос для поиска последней версии пакета
-    let mut stmt = conn.prepare(
-        "SELECT name, version, path FROM packages WHERE name = ?1 ORDER BY version DESC LIMIT 1",
-    )?;
-    let mut rows = stmt.query([package_name])?;
+// Заголовки вида "Имя: значение"
+fn option_headers(repo_name: &str, section: &Properties) -> Vec<(String, String)> {
+    section.get_all("header")
+        .filter_map(|header| match header.split_once(':') {
+            Some((name, value)) => Some((name.trim().to_string(), value.trim().to_string())),
+            None => {
+                error!("Репозиторий {}: некорректный заголовок '{}', ожидается 'Имя: значение'", repo_name, header);
+                None
+            }
+        })
+        .collect()
+}
 
-    if let Some(row) = rows.next()? {
-        Ok(Some(DbPackageEntry::from_row(&row)?))
-    } else {
-        Ok(None)
-    }
+// Подстановка имени репозитория и архитектуры в адрес
+pub fn expand_url(url: &str, repo_name: &str, arch: &str) -> String {
+    url.replace("$repo", repo_name).replace("$arch", arch)
 }
 
-// Поиск пакета с учетом минимальной версии
-fn find_package_with_ver(
-    db_path: &Path,
-    package_name: &str,
-    version: &str,
-) -> Result<Option<DbPackageEntry>, Box<dyn std::error::Error>> {
-    let conn = Connection::open(db_path)?;
-
-    let mut stmt = conn.prepare("SELECT name, version, path FROM packages WHERE name = ?1")?;
-    let mut rows = stmt.query([package_name])?;
-
-    let min_version = Version::from(version).ok_or("Invalid version format")?;
-    let mut latest_entry: Option<DbPackageEntry> = None;
-
-    // Перебор всех версий пакета
-    while let Some(row) = rows.next()? {
-        let entry = DbPackageEntry::from_row(&row)?;
-        let entry_version = Version::from(&entry.version).ok_or("Invalid version format in database")?;
-
-        // Проверка версии и обновление latest_entry при необходимости
-        if entry_version >= min_version {
-            if let Some(ref latest) = latest_entry {
-                let latest_version = Version::from(&latest.version).ok_or("Invalid version format in database")?;
-                if entry_version > latest_version {
-                    latest_entry = Some(entry);
-                }
-            } else {
-                latest_entry = Some(entry);
+// Строки mirrorlist: "Server = url" или просто url, # - комментарий
+pub fn read_mirrorlist(path: &Path) -> io::Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .map(|line| match line.split_once('=') {
+            Some((key, value)) if key.trim().eq_ignore_ascii_case("server") => value.trim(),
+            _ => line,
+        })
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect())
+}
+
+// Получение списка репозиториев из конфигурационного файла; учетные данные читаются из credentials_file
+pub fn get_repos(config_file: &Path, credentials_file: &Path, arch: &str) -> Result<Vec<Repository>, KonpacError> {
+    let repos = Ini::load_from_file(config_file)
+        .map_err(|e| KonpacError::from(e).context(format!("Список репозиториев {:?}", config_file)))?;
+    let mut repositories: Vec<Repository> = vec![];
+    
+    // Обработка каждой секции в INI файле, параметры вне секций игнорируются
+    for (section_name, section) in &repos {
+        let Some(repo_name) = section_name else {
+            continue;
+        };
+        // Зеркала: url, затем mirror = ..., затем строки mirrorlist
+        let mirrorlist = section.get("mirrorlist").map(PathBuf::from);
+        let mut templates: Vec<String> = section.get("url").into_iter()
+            .chain(section.get_all("mirror"))
+            .map(|url| url.to_string())
+            .collect();
+        if let Some(mirrorlist) = &mirrorlist {
+            match read_mirrorlist(mirrorlist) {
+                Ok(servers) => templates.extend(servers),
+                Err(e) => error!("Репозиторий {}: не удалось прочитать {:?}: {}", repo_name, mirrorlist, e),
             }
         }
+        // Замена переменных в URL репозитория
+        let mirrors: Vec<String> = templates.iter()
+            .filter(|url| !url.is_empty())
+            .map(|url| expand_url(url, repo_name, arch))
+            .collect();
+
+        let repo = Repository{
+            name: repo_name.to_string(),
+            url: mirrors.first().cloned().unwrap_or_default(),
+            mirrors,
+            mirrorlist,
+            arch: arch.to_string(),
+            priority: option_value(repo_name, section, "priority", 0),
+            enabled: option_bool(repo_name, section, "enabled", true),
+            siglevel: option_value(repo_name, section, "siglevel", SigLevel::Optional),
+            arches: option_list(section, "arch"),
+            include: option_patterns(repo_name, section, "include"),
+            exclude: option_patterns(repo_name, section, "exclude"),
+            http: HttpOptions {
+                auth: repo_auth(repo_name, section, credentials_file),
+                headers: option_headers(repo_name, section),
+                ca_cert: section.get("ca_cert").map(PathBuf::from),
+                client_cert: section.get("client_cert").map(PathBuf::from),
+                client_key: section.get("client_key").map(PathBuf::from),
+            },
+        };
+        repositories.push(repo);
     }
+    Ok(repositories)
+}
 
-    Ok(latest_entry)
+// Разделение запроса "repo/package" на имя репозитория и пакета
+pub fn split_repo_prefix(query: &str) -> (Option<&str>, &str) {
+    match query.split_once('/') {
+        Some((repo, package)) => (Some(repo), package),
+        None => (None, query),
+    }
 }
 
-// Поиск пакета по версии с использованием оператора сравнения
+// Репозитории, в которых можно искать пакет, в порядке убывания приоритета.
+// При одинаковом приоритете сохраняется порядок из файла
+pub fn candidate_repos(repositories: Vec<Repository>, repo_name: Option<&str>, package_name: &str) -> Vec<Repository> {
+    let mut candidates: Vec<Repository> = repositories.into_iter()
+        .filter(|repo| repo.enabled && repo.serves_arch() && repo.allows(package_name))
+        .filter(|repo| repo_name.is_none_or(|name| repo.name == name))
+        .collect();
+    candidates.sort_by_key(|repo| Reverse(repo.priority));
+    candidates
+}
+
+// Все версии пакета в репозитории, от новой к старой
+pub async fn package_versions(fetcher: &Fetcher, package_name: &str, repo: &Repository) -> Result<Vec<RepoPackage>, KonpacError> {
+    let db_path = sync_index(fetcher, repo).await?;
+    SyncDb::open(&db_path)?.packages(package_name, &repo.arch)
+}
+
+// Все версии пакета из уже синхронизированного индекса системы с корнем root, без обращения к зеркалам
+pub fn synced_package_versions(root: &Path, package_name: &str, repo: &Repository) -> Result<Vec<RepoPackage>, KonpacError> {
+    let db_path = index_path(root, repo);
+    if !db_path.exists() {
+        return Err(KonpacError::NotFound(format!("Индекс репозитория {} не синхронизирован, выполните konpac sync", repo.name)));
+    }
+    SyncDb::open(&db_path)?.packages(package_name, &repo.arch)
+}
+
+// Поиск новейшей версии пакета, подходящей под оператор сравнения.
+// Пустой оператор означает любую версию; без sync берется уже синхронизированный индекс
 pub async fn find_package_by_version(
+    fetcher: &Fetcher,
+    sync: bool,
     package_name: &str,
     version: &str,
     comparison_operator: &str,
-    repo: Repository
-) -> Result<Option<DbPackageEntry>, Box<dyn std::error::Error>> {
-    let db_str_path = format!("/tmp/{}.db", repo.name);
-    let db_path = Path::new(&db_str_path);
-    
-    // Загрузка базы данных репозитория
-    let db_link = format!("{}/packages.db", repo.url);
-    info!("Fetching database from URL: {}", db_link);
-    match fetch_url(db_link, &db_path).await {
-        Ok(_) => { info!("Fetch file success") },
-        Err(e) => { 
-            error!("Error in fetch file: {}", e); 
-            return Err(e); 
-        }
-    };
-
-    let conn = Connection::open(db_path)?;
-
-    // Проверка существования таблицы packages
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS packages (
-            name TEXT NOT NULL,
-            version TEXT NOT NULL,
-            path TEXT NOT NULL,
-            PRIMARY KEY (name, version)
-        )",
-        [],
-    )?;
-
-    // Формирование SQL запроса в зависимости от оператора сравнения
-    let query = match comparison_operator {
-        "=" => "SELECT name, version, path FROM packages WHERE name = ?1 AND version = ?2",
-        "<" => "SELECT name, version, path FROM packages WHERE name = ?1 AND version < ?2",
-        ">" => "SELECT name, version, path FROM packages WHERE name = ?1 AND version > ?2",
-        "<=" => "SELECT name, version, path FROM packages WHERE name = ?1 AND version <= ?2",
-        ">=" => "SELECT name, version, path FROM packages WHERE name = ?1 AND version >= ?2",
-        _ => return Err("Неподдерживаемый оператор сравнения. Используйте =, <, >, <=, >=".into()),
-    };
-
-    let mut stmt = conn.prepare(query)?;
-    let mut rows = stmt.query([package_name, version])?;
+    repo: &Repository
+) -> Result<Option<RepoPackage>, KonpacError> {
+    if !["", "=", "<", ">", "<=", ">="].contains(&comparison_operator) {
+        return Err(KonpacError::Invalid("Неподдерживаемый оператор сравнения. Используйте =, <, >, <=, >=".to_string()));
+    }
 
-    if let Some(row) = rows.next()? {
-        Ok(Some(DbPackageEntry::from_row(&row)?))
+    let versions = if sync {
+        package_versions(fetcher, package_name, repo).await?
     } else {
-        Ok(None)
+        synced_package_versions(fetcher.root(), package_name, repo)?
+    };
+    let package = versions
+        .into_iter()
+        .find(|package| version_matches(comparison_operator, &package.version, version));
+    if package.is_none() {
+        info!("Пакет '{}{}{}' ({}) не найден в репозитории {}", package_name, comparison_operator, version, repo.arch, repo.name);
     }
+    Ok(package)
 }
 
-// Поиск последней версии пакета в репозитории
-pub async fn search_pkg(pkg_name: &str, repo: Repository) -> std::result::Result<DbPackageEntry, Box<dyn std::error::Error>> {
-    let db_str_path = format!("/tmp/{}.db", repo.name);
-    let db_path = Path::new(&db_str_path);
-    
-    // Загрузка базы данных репозитория
-    let db_link = format!("{}packages.db", repo.url);
-    match fetch_url(db_link, &db_path).await {
-        Ok(_) => { info!("Fetch file success") },
-        Err(e) => { error!("Error in fetch file: {}", e)} 
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use crate::package::stage::WorkDir;
+
+    #[test]
+    fn candidates_follow_priority_and_filters() {
+        let work_dir = WorkDir::temp("utils-candidates");
+        let repos_file = work_dir.path().join("repos");
+        fs::write(&repos_file, "\
+[core]
+url = https://example.org/core
+
+[extra]
+url = https://example.org/extra
+priority = 10
+exclude = lib*
+
+[testing]
+url = https://example.org/testing
+enabled = no
+priority = 100
+
+[community]
+url = https://example.org/community
+
+[games]
+url = https://example.org/games
+priority = 10
+include = game-* lib-game
+
+[arm]
+url = https://example.org/arm
+arch = aarch64
+priority = 50
+").unwrap();
+        let repos = get_repos(&repos_file, &work_dir.path().join("credentials"), "x86_64").unwrap();
+        let names = |package: &str, repo: Option<&str>| -> Vec<String> {
+            candidate_repos(repos.clone(), repo, package).into_iter().map(|repo| repo.name).collect()
+        };
 
-    info!("Repository URL: {}", repo.url);
+        // Больший приоритет раньше, при равном - порядок файла; отключенные и чужой архитектуры не участвуют
+        assert_eq!(names("game-chess", None), ["extra", "games", "core", "community"]);
+        assert_eq!(names("editor", None), ["extra", "core", "community"]);
+        // exclude убирает репозиторий, include оставляет только подходящие пакеты
+        assert_eq!(names("libfoo", None), ["core", "community"]);
+        assert_eq!(names("lib-game", None), ["games", "core", "community"]);
+        // Префикс repo/ выбирает один репозиторий, но его фильтры по-прежнему действуют
+        assert_eq!(names("editor", Some("community")), ["community"]);
+        assert!(names("libfoo", Some("extra")).is_empty());
+        assert!(names("editor", Some("testing")).is_empty());
+        assert!(names("editor", Some("arm")).is_empty());
+    }
 
-    // Поиск пакета
-    let package = find_package(&db_path, &pkg_name)?;
-    
-    // Обработка результата поиска
-    let package = match package {
-        Some(pkg) => pkg,
-        None => {
-            error!("Пакет '{}' не найден в репозитории {}", pkg_name, repo.name);
-            panic!()
+    #[test]
+    fn credentials_file_must_be_private() {
+        let work_dir = WorkDir::temp("utils-credentials");
+        let repos_file = work_dir.path().join("repos");
+        let credentials_file = work_dir.path().join("credentials");
+        fs::write(&repos_file, "[private]\nurl = https://example.org/$repo\nauth = basic\nheader = X-Repo-Token: secret\nheader = broken\n\n[public]\nurl = https://example.org/public\n").unwrap();
+        fs::write(&credentials_file, "[private]\nusername = user\npassword = pass\n").unwrap();
+
+        // Файл, открытый для чтения другим, не читается, и авторизация не применяется
+        fs::set_permissions(&credentials_file, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(read_credentials(&credentials_file), Err(KonpacError::Permission(_))));
+        let repos = get_repos(&repos_file, &credentials_file, "x86_64").unwrap();
+        assert!(repos[0].http.auth.is_none());
+        assert_eq!(repos[0].http.headers, [("X-Repo-Token".to_string(), "secret".to_string())]);
+
+        // Права 600 подходят, только если файл принадлежит root
+        fs::set_permissions(&credentials_file, fs::Permissions::from_mode(0o600)).unwrap();
+        if fs::metadata(&credentials_file).unwrap().uid() != 0 {
+            assert!(matches!(read_credentials(&credentials_file), Err(KonpacError::Permission(_))));
+            return;
         }
-    };
-
-    Ok(package)
+        let repos = get_repos(&repos_file, &credentials_file, "x86_64").unwrap();
+        assert!(matches!(&repos[0].http.auth, Some(Auth::Basic { username, password: Some(password) }) if username == "user" && password == "pass"));
+        assert!(repos[1].http.auth.is_none());
+        assert!(repos[1].http.headers.is_empty());
+    }
 }
-
-// Поиск пакета с указанной версией в репозитории
-pub async fn search_pkg_with_ver(pkg_name: String, repo: Repository, version: &str) -> std::result::Result<DbPackageEntry, Box<dyn std::error::Error>> {
-    let db_str_path = format!("/tmp/{}.db", repo.name);
-    let db_path = Path::new(&db_str_path);
-    
-    // Загрузка базы данных репозитория
-    let db_link = format!("{}/packages.db",repo.url);
-    match fetch_url(db_link, &db_path).await {
-        Ok(_) => { info!("Fetch file success") },
-        Err(e) => { error!("Error in fetch file: {}", e)} 
-    };
-
-    // Поиск пакета с учетом версии
-    let package = find_package_with_ver(&db_path, &pkg_name,version)?;
-    
-    // Обработка результата поиска
-    let package = match package {
-        Some(pkg) => pkg,
-        None => {
-            error!("Пакет '{}' не найден в репозитории {}", pkg_name, repo.name);
-            panic!()
-        }
-    };
-
-    Ok(package)
-}
\ No newline at end of file